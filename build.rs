@@ -0,0 +1,182 @@
+// Embeds everything in `client/build` into the binary. Each file gets a
+// content hash, and is served both at its own path and at a hashed path that
+// can be cached forever. `index.html` is rewritten to use the hashed paths.
+
+use sha1::{Digest, Sha1};
+use std::{
+    env,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const CLIENT_BUILD: &str = "client/build";
+
+struct Asset {
+    path: String,
+    hashed_path: String,
+    hash: String,
+    file: PathBuf,
+}
+
+fn main() {
+    version_info();
+
+    // The server reads `client/build` from disk instead.
+    if env::var_os("CARGO_FEATURE_DEV_ASSETS").is_some() {
+        return;
+    }
+    embed_client();
+}
+
+// Shown by `/version`.
+fn version_info() {
+    let git_sha = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|sha| sha.trim().to_string())
+        .unwrap_or_else(|| "unknown".into());
+    let build_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    println!("cargo:rustc-env=GIT_SHA={}", git_sha);
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", build_time);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
+
+fn embed_client() {
+    println!("cargo:rerun-if-changed={}", CLIENT_BUILD);
+
+    let root = Path::new(CLIENT_BUILD);
+    if !root.join("index.html").exists() {
+        panic!(
+            "{}/index.html not found. Build the client first: `pnpm build`, \
+             or serve it from disk with `--features dev-assets`",
+            CLIENT_BUILD
+        );
+    }
+
+    let mut files = Vec::new();
+    collect_files(root, &mut files);
+    files.sort();
+
+    let mut assets = Vec::new();
+    for file in files {
+        let path = file
+            .strip_prefix(root)
+            .unwrap()
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        if path == "index.html" {
+            continue;
+        }
+        let hash = hash(&fs::read(&file).unwrap());
+        assets.push(Asset {
+            hashed_path: hashed_path(&path, &hash),
+            path,
+            hash,
+            file: file.canonicalize().unwrap(),
+        });
+    }
+
+    let mut index = fs::read_to_string(root.join("index.html")).unwrap();
+    for asset in &assets {
+        for quote in &['"', '\''] {
+            index = index.replace(
+                &format!("{}/{}{}", quote, asset.path, quote),
+                &format!("{}/{}{}", quote, asset.hashed_path, quote),
+            );
+        }
+    }
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let index_file = out_dir.join("index.html");
+    fs::write(&index_file, &index).unwrap();
+
+    let mut code = String::new();
+    writeln!(
+        code,
+        "pub static INDEX_HTML: Asset = {};",
+        asset_literal(&Asset {
+            path: "index.html".into(),
+            hashed_path: "index.html".into(),
+            hash: hash(index.as_bytes()),
+            file: index_file,
+        })
+    )
+    .unwrap();
+    writeln!(code, "pub static ASSETS: &[Asset] = &[").unwrap();
+    for asset in &assets {
+        writeln!(code, "    {},", asset_literal(asset)).unwrap();
+    }
+    writeln!(code, "];").unwrap();
+    fs::write(out_dir.join("assets.rs"), code).unwrap();
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+fn hash(bytes: &[u8]) -> String {
+    format!("{:x}", Sha1::digest(bytes))
+}
+
+// `dist/index.js` -> `dist/index.0123456789.js`
+fn hashed_path(path: &str, hash: &str) -> String {
+    let short = &hash[..10];
+    let name_start = path.rfind('/').map_or(0, |i| i + 1);
+    match path[name_start..].find('.') {
+        Some(dot) => {
+            let dot = name_start + dot;
+            format!("{}.{}{}", &path[..dot], short, &path[dot..])
+        }
+        None => format!("{}.{}", path, short),
+    }
+}
+
+fn content_type(path: &str) -> &'static str {
+    let extension = path.rsplit('.').next().unwrap_or_default();
+    match extension {
+        "html" => "text/html; charset=utf-8",
+        "js" | "mjs" => "application/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff2" => "font/woff2",
+        "woff" => "font/woff",
+        _ => "application/octet-stream",
+    }
+}
+
+fn asset_literal(asset: &Asset) -> String {
+    format!(
+        "Asset {{ path: {:?}, hashed_path: {:?}, etag: {:?}, content_type: {:?}, bytes: include_bytes!({:?}) }}",
+        asset.path,
+        asset.hashed_path,
+        format!("\"{}\"", asset.hash),
+        content_type(&asset.path),
+        asset.file.to_string_lossy(),
+    )
+}
@@ -0,0 +1,296 @@
+//! Signing up, verifying an email address and resetting a forgotten password.
+//! The last two work through single-use links sent by email.
+
+use crate::{
+    auth::{self, CurrentUser},
+    mail::{Email, Mailer},
+    settings::Settings,
+    users, ServerError,
+};
+use actix_identity::Identity;
+use actix_web::{web, HttpResponse};
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::{
+    postgres::{PgPool, Postgres},
+    Transaction,
+};
+use uuid::Uuid;
+
+const VERIFY_EMAIL_HOURS: i64 = 48;
+const PASSWORD_RESET_MINUTES: i64 = 60;
+
+#[derive(Clone, Copy, sqlx::Type)]
+#[sqlx(rename = "user_token_purpose", rename_all = "snake_case")]
+enum TokenPurpose {
+    VerifyEmail,
+    PasswordReset,
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Stores a new token for the user and returns it. Only its hash is kept.
+async fn issue_token(
+    db_pool: &PgPool,
+    user_id: i32,
+    purpose: TokenPurpose,
+    valid_for: Duration,
+) -> Result<String, ServerError> {
+    let token = format!(
+        "{}{}",
+        Uuid::new_v4().to_simple(),
+        Uuid::new_v4().to_simple()
+    );
+    sqlx::query(
+        r#"
+INSERT INTO user_token (token_hash, user_id, purpose, expires_at)
+VALUES ($1, $2, $3, $4)
+"#,
+    )
+    .bind(hash_token(&token))
+    .bind(user_id)
+    .bind(purpose)
+    .bind(Utc::now() + valid_for)
+    .execute(db_pool)
+    .await?;
+    Ok(token)
+}
+
+/// Uses up the token and returns whose it was. Expired, used, or unknown
+/// tokens all get the same error.
+async fn redeem_token(
+    tx: &mut Transaction<'_, Postgres>,
+    token: &str,
+    purpose: TokenPurpose,
+) -> Result<i32, ServerError> {
+    let user_id: Option<(i32,)> = sqlx::query_as(
+        r#"
+UPDATE user_token
+SET used_at = now()
+WHERE token_hash = $1
+AND purpose = $2
+AND used_at IS NULL
+AND expires_at > now()
+RETURNING user_id
+"#,
+    )
+    .bind(hash_token(token))
+    .bind(purpose)
+    .fetch_optional(tx)
+    .await?;
+    user_id.map(|(id,)| id).ok_or(ServerError::ValidationError {
+        field: "token",
+        message: "This link is invalid or has expired".into(),
+    })
+}
+
+/// Failing to send is logged rather than returned. The account change has
+/// already happened, and the link can be asked for again.
+async fn send(mailer: web::Data<dyn Mailer>, email: Email) {
+    let to = email.to.clone();
+    if let Err(e) = web::block(move || mailer.send(&email)).await {
+        log::error!("failed to send email to {}: {}", to, e);
+    }
+}
+
+async fn send_verification(
+    db_pool: &PgPool,
+    settings: &Settings,
+    mailer: web::Data<dyn Mailer>,
+    user_id: i32,
+    email: &str,
+) -> Result<(), ServerError> {
+    let token = issue_token(
+        db_pool,
+        user_id,
+        TokenPurpose::VerifyEmail,
+        Duration::hours(VERIFY_EMAIL_HOURS),
+    )
+    .await?;
+    let email = Email {
+        to: email.into(),
+        subject: "Confirm your email address".into(),
+        body: format!(
+            "Open this link to confirm your email address:\n\n\
+             {}/verify-email?token={}\n\n\
+             It works for {} hours.",
+            settings.public_url, token, VERIFY_EMAIL_HOURS
+        ),
+    };
+    send(mailer, email).await;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SignUpInput {
+    email: String,
+    display_name: String,
+    password: String,
+}
+
+/// Creates the account, logs it in, and sends a link to verify the email.
+pub async fn sign_up(
+    identity: Identity,
+    db_pool: web::Data<PgPool>,
+    settings: web::Data<Settings>,
+    mailer: web::Data<dyn Mailer>,
+    json: web::Json<SignUpInput>,
+) -> Result<HttpResponse, ServerError> {
+    let user = users::create_user(
+        &db_pool,
+        &json.email,
+        &json.display_name,
+        &json.password,
+    )
+    .await?;
+    send_verification(&db_pool, &settings, mailer, user.id, &user.email)
+        .await?;
+    auth::remember(&identity, &user);
+    Ok(HttpResponse::Ok().json(user))
+}
+
+/// Sends a new link, for when the first one expired or went missing.
+pub async fn resend_verification(
+    user: CurrentUser,
+    db_pool: web::Data<PgPool>,
+    settings: web::Data<Settings>,
+    mailer: web::Data<dyn Mailer>,
+) -> Result<HttpResponse, ServerError> {
+    let (email, verified): (String, bool) = sqlx::query_as(
+        r#"
+SELECT email, email_verified_at IS NOT NULL
+FROM app_user
+WHERE id = $1
+"#,
+    )
+    .bind(user.0)
+    .fetch_optional(&**db_pool)
+    .await?
+    .ok_or(ServerError::Unauthorized)?;
+    if !verified {
+        send_verification(&db_pool, &settings, mailer, user.0, &email).await?;
+    }
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[derive(Deserialize)]
+pub struct VerifyEmailInput {
+    token: String,
+}
+
+pub async fn verify_email(
+    db_pool: web::Data<PgPool>,
+    json: web::Json<VerifyEmailInput>,
+) -> Result<HttpResponse, ServerError> {
+    let mut tx = db_pool.begin().await?;
+    let user_id =
+        redeem_token(&mut tx, &json.token, TokenPurpose::VerifyEmail).await?;
+    sqlx::query(
+        r#"
+UPDATE app_user
+SET email_verified_at = COALESCE(email_verified_at, now())
+WHERE id = $1
+"#,
+    )
+    .bind(user_id)
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize)]
+pub struct PasswordResetRequestInput {
+    email: String,
+}
+
+/// Emails a reset link if the address belongs to an account. The response
+/// is the same either way, so it can't be used to find out who has one.
+pub async fn request_password_reset(
+    db_pool: web::Data<PgPool>,
+    settings: web::Data<Settings>,
+    mailer: web::Data<dyn Mailer>,
+    json: web::Json<PasswordResetRequestInput>,
+) -> Result<HttpResponse, ServerError> {
+    let user: Option<(i32, String)> =
+        sqlx::query_as("SELECT id, email FROM app_user WHERE email = $1")
+            .bind(json.email.trim().to_lowercase())
+            .fetch_optional(&**db_pool)
+            .await?;
+
+    if let Some((user_id, email)) = user {
+        let token = issue_token(
+            &db_pool,
+            user_id,
+            TokenPurpose::PasswordReset,
+            Duration::minutes(PASSWORD_RESET_MINUTES),
+        )
+        .await?;
+        let email = Email {
+            to: email,
+            subject: "Reset your password".into(),
+            body: format!(
+                "Open this link to choose a new password:\n\n\
+                 {}/reset-password?token={}\n\n\
+                 It works once, for {} minutes. If you didn't ask for this, \
+                 you can ignore it.",
+                settings.public_url, token, PASSWORD_RESET_MINUTES
+            ),
+        };
+        send(mailer, email).await;
+    }
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[derive(Deserialize)]
+pub struct PasswordResetConfirmInput {
+    token: String,
+    password: String,
+}
+
+pub async fn confirm_password_reset(
+    db_pool: web::Data<PgPool>,
+    json: web::Json<PasswordResetConfirmInput>,
+) -> Result<HttpResponse, ServerError> {
+    users::validate_password(&json.password)?;
+    let password_hash = users::hash_password(&json.password)?;
+
+    let mut tx = db_pool.begin().await?;
+    let user_id =
+        redeem_token(&mut tx, &json.token, TokenPurpose::PasswordReset).await?;
+    // Getting the email proves the address is theirs, too. Whoever knew
+    // the old password may still be logged in, so every session ends.
+    sqlx::query(
+        r#"
+UPDATE app_user
+SET password_hash = $2,
+    email_verified_at = COALESCE(email_verified_at, now()),
+    session_generation = session_generation + 1
+WHERE id = $1
+"#,
+    )
+    .bind(user_id)
+    .bind(&password_hash)
+    .execute(&mut tx)
+    .await?;
+    // Any other reset links sent before this one stop working.
+    sqlx::query(
+        r#"
+UPDATE user_token
+SET used_at = now()
+WHERE user_id = $1
+AND purpose = $2
+AND used_at IS NULL
+"#,
+    )
+    .bind(user_id)
+    .bind(TokenPurpose::PasswordReset)
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(HttpResponse::NoContent().finish())
+}
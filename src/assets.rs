@@ -0,0 +1,137 @@
+//! The web app from `client/build`. Normally it's embedded into the binary by
+//! `build.rs`. With the `dev-assets` feature it's read from disk on every
+//! request instead, so a client rebuild shows up without rebuilding the server.
+
+#[cfg(feature = "dev-assets")]
+pub use disk::{index_html, serve};
+#[cfg(not(feature = "dev-assets"))]
+pub use embedded::{index_html, serve};
+
+#[cfg(not(feature = "dev-assets"))]
+mod embedded {
+    use actix_web::{http::header, HttpRequest, HttpResponse};
+
+    /// A file from `client/build`, embedded at compile time by `build.rs`.
+    pub struct Asset {
+        pub path: &'static str,
+        pub hashed_path: &'static str,
+        pub etag: &'static str,
+        pub content_type: &'static str,
+        pub bytes: &'static [u8],
+    }
+
+    include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+    // Hashed paths change whenever the content does, so they never go stale.
+    const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+    // Everything else has to be checked with the server, using the ETag.
+    const NO_CACHE: &str = "no-cache";
+
+    pub async fn index_html(req: HttpRequest) -> HttpResponse {
+        respond(&req, &INDEX_HTML, NO_CACHE)
+    }
+
+    /// Serves an embedded asset by its hashed or plain path. Anything else is
+    /// a client side route, so it gets the web app.
+    pub async fn serve(req: HttpRequest) -> HttpResponse {
+        let path = req.path().trim_start_matches('/');
+        for asset in ASSETS {
+            if asset.hashed_path == path {
+                return respond(&req, asset, IMMUTABLE);
+            }
+            if asset.path == path {
+                return respond(&req, asset, NO_CACHE);
+            }
+        }
+        respond(&req, &INDEX_HTML, NO_CACHE)
+    }
+
+    fn respond(
+        req: &HttpRequest,
+        asset: &'static Asset,
+        cache_control: &'static str,
+    ) -> HttpResponse {
+        if etag_matches(req, asset.etag) {
+            return HttpResponse::NotModified()
+                .header(header::ETAG, asset.etag)
+                .header(header::CACHE_CONTROL, cache_control)
+                .finish();
+        }
+        HttpResponse::Ok()
+            .content_type(asset.content_type)
+            .header(header::ETAG, asset.etag)
+            .header(header::CACHE_CONTROL, cache_control)
+            .body(asset.bytes)
+    }
+
+    fn etag_matches(req: &HttpRequest, etag: &str) -> bool {
+        let if_none_match = match req.headers().get(header::IF_NONE_MATCH) {
+            Some(value) => value.to_str().unwrap_or_default(),
+            None => return false,
+        };
+        if_none_match.split(',').map(str::trim).any(|candidate| {
+            // A weak match is fine for GET
+            candidate == "*" || candidate.trim_start_matches("W/") == etag
+        })
+    }
+}
+
+#[cfg(feature = "dev-assets")]
+mod disk {
+    use actix_files::NamedFile;
+    use actix_web::{
+        http::header::{self, HeaderValue},
+        HttpRequest, HttpResponse,
+    };
+    use std::path::{Component, Path, PathBuf};
+
+    const CLIENT_BUILD: &str = "./client/build";
+
+    pub async fn index_html(
+        req: HttpRequest,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        respond(&req, Path::new(CLIENT_BUILD).join("index.html"))
+    }
+
+    /// Serves a file from `client/build`. Anything that isn't a file there is
+    /// a client side route, so it gets the web app.
+    pub async fn serve(
+        req: HttpRequest,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let path = match build_path(req.path()) {
+            Some(path) if path.is_file() => path,
+            _ => Path::new(CLIENT_BUILD).join("index.html"),
+        };
+        respond(&req, path)
+    }
+
+    /// Only plain path segments are allowed, so nothing outside
+    /// `client/build` can be reached.
+    fn build_path(request_path: &str) -> Option<PathBuf> {
+        let relative = Path::new(request_path.trim_start_matches('/'));
+        if relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            Some(Path::new(CLIENT_BUILD).join(relative))
+        } else {
+            None
+        }
+    }
+
+    fn respond(
+        req: &HttpRequest,
+        path: PathBuf,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let mut response = NamedFile::open(path)?
+            .use_etag(false)
+            .use_last_modified(false)
+            .into_response(req)?;
+        // Always fetch the latest build, so a reload shows client changes.
+        response.headers_mut().insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static("no-store, must-revalidate"),
+        );
+        Ok(response)
+    }
+}
@@ -0,0 +1,122 @@
+//! Logging in and out. A logged in user's id is kept in a signed cookie, and
+//! handlers that need to know who's asking take a `CurrentUser`. The cookie
+//! also holds the user's session generation, so bumping that in the database
+//! logs them out everywhere, cookies already handed out included.
+
+use crate::{logging, settings::Settings, users, ServerError};
+use actix_identity::{
+    CookieIdentityPolicy, Identity, IdentityService, RequestIdentity,
+};
+use actix_web::{
+    cookie::SameSite, dev::Payload, web, FromRequest, HttpRequest, HttpResponse,
+};
+use futures::future::{FutureExt, LocalBoxFuture};
+use serde::Deserialize;
+use sqlx::postgres::PgPool;
+use uuid::Uuid;
+
+const COOKIE_NAME: &str = "howido-session";
+const SESSION_DAYS: i64 = 30;
+
+/// The configured secret, or a random one that only lasts as long as this
+/// process.
+pub fn session_key(settings: &Settings) -> Vec<u8> {
+    match &settings.session_secret {
+        Some(secret) => secret.as_bytes().to_vec(),
+        None => {
+            log::warn!(
+                "session_secret isn't set, logins won't survive a restart"
+            );
+            // v4 UUIDs come from the OS's secure generator. Two make the
+            // 32 bytes the cookie key needs.
+            let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+            [&a.as_bytes()[..], &b.as_bytes()[..]].concat()
+        }
+    }
+}
+
+pub fn identity_service(
+    settings: &Settings,
+    key: &[u8],
+) -> IdentityService<CookieIdentityPolicy> {
+    // A browser drops a `Secure` cookie sent over plain HTTP, which would
+    // leave every login silently failing.
+    let secure = settings.is_https();
+    IdentityService::new(
+        CookieIdentityPolicy::new(key)
+            .name(COOKIE_NAME)
+            .path("/")
+            .secure(secure)
+            .same_site(SameSite::Lax)
+            .max_age(SESSION_DAYS * 24 * 60 * 60),
+    )
+}
+
+/// Logs the user in, as `<id>.<session generation>`.
+pub fn remember(identity: &Identity, user: &users::UserRow) {
+    identity.remember(format!("{}.{}", user.id, user.session_generation));
+}
+
+fn parse_identity(identity: &str) -> Option<(i32, i32)> {
+    let (id, generation) = identity.split_once('.')?;
+    Some((id.parse().ok()?, generation.parse().ok()?))
+}
+
+/// The logged in user. Extracting it fails with a 401 for anyone else,
+/// including a cookie from before the user's sessions were revoked.
+pub struct CurrentUser(pub i32);
+
+impl FromRequest for CurrentUser {
+    type Error = ServerError;
+    type Future = LocalBoxFuture<'static, Result<Self, ServerError>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        async move {
+            let (id, generation) = req
+                .get_identity()
+                .as_deref()
+                .and_then(parse_identity)
+                .ok_or(ServerError::Unauthorized)?;
+            let db_pool = req
+                .app_data::<web::Data<PgPool>>()
+                .ok_or(ServerError::Unauthorized)?;
+            let current: Option<(i32,)> = sqlx::query_as(
+                "SELECT session_generation FROM app_user WHERE id = $1",
+            )
+            .bind(id)
+            .fetch_optional(&***db_pool)
+            .await?;
+            if current != Some((generation,)) {
+                return Err(ServerError::Unauthorized);
+            }
+            req.extensions_mut().insert(logging::UserId(id));
+            Ok(CurrentUser(id))
+        }
+        .boxed_local()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LoginInput {
+    email: String,
+    password: String,
+}
+
+pub async fn login(
+    identity: Identity,
+    db_pool: web::Data<PgPool>,
+    json: web::Json<LoginInput>,
+) -> Result<HttpResponse, ServerError> {
+    let user = users::authenticate(&db_pool, &json.email, &json.password)
+        .await?
+        .ok_or(ServerError::Unauthorized)?;
+    remember(&identity, &user);
+    Ok(HttpResponse::Ok().json(user))
+}
+
+pub async fn logout(identity: Identity) -> HttpResponse {
+    identity.forget();
+    HttpResponse::NoContent().finish()
+}
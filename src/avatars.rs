@@ -0,0 +1,246 @@
+//! Profile pictures. An upload is cropped to a centred square and stored at
+//! each size in `SIZES`, next to step media. `app_user.avatar_filename` holds
+//! the random stem the sizes share.
+
+use crate::{
+    auth::CurrentUser,
+    media::{self, MediaType},
+    metrics,
+    settings::Settings,
+    ServerError,
+};
+use actix_multipart::Multipart;
+use actix_web::{error::BlockingError, web, HttpResponse};
+use futures::TryStreamExt;
+use image::{
+    error::{LimitError, LimitErrorKind},
+    imageops::FilterType,
+    io::Reader,
+    DynamicImage, GenericImageView, ImageError, ImageOutputFormat,
+};
+use serde::{Serialize, Serializer};
+use sqlx::postgres::PgPool;
+use std::io::Cursor;
+use uuid::Uuid;
+
+const SMALL: u32 = 64;
+const LARGE: u32 = 256;
+const SIZES: [u32; 2] = [SMALL, LARGE];
+const JPEG_QUALITY: u8 = 85;
+/// Checked before decoding, since a few kilobytes of compressed data can
+/// claim to be any size. Enough for a phone camera, and about 120 MB decoded
+/// as RGB, with up to as much again for the square crop. Formats with alpha
+/// or 16-bit channels take more per pixel.
+const MAX_PIXELS: u64 = 40_000_000;
+
+#[derive(Debug, Serialize)]
+pub struct Avatar {
+    small: String,
+    large: String,
+}
+
+impl Avatar {
+    fn new(stem: &str) -> Self {
+        Avatar {
+            small: filename(stem, SMALL),
+            large: filename(stem, LARGE),
+        }
+    }
+}
+
+/// For `#[serde(serialize_with)]` on an `avatar_filename` column, so rows
+/// can be sent as they are read.
+pub fn serialize<S: Serializer>(
+    stem: &Option<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    stem.as_deref().map(Avatar::new).serialize(serializer)
+}
+
+fn filename(stem: &str, size: u32) -> String {
+    format!("{}-{}.jpg", stem, size)
+}
+
+fn filenames(stem: &str) -> Vec<String> {
+    SIZES.iter().map(|size| filename(stem, *size)).collect()
+}
+
+/// The stem of a file named like one size of an avatar.
+pub fn stem_of(filename: &str) -> Option<&str> {
+    let (stem, size) = filename.strip_suffix(".jpg")?.split_once('-')?;
+    let valid = !stem.is_empty()
+        && stem.bytes().all(|b| b.is_ascii_hexdigit())
+        && SIZES.iter().any(|s| s.to_string() == size);
+    if valid {
+        Some(stem)
+    } else {
+        None
+    }
+}
+
+pub async fn in_use(db_pool: &PgPool, stem: &str) -> Result<bool, ServerError> {
+    let (in_use,): (bool,) = sqlx::query_as(
+        "SELECT EXISTS (SELECT 1 FROM app_user WHERE avatar_filename = $1)",
+    )
+    .bind(stem)
+    .fetch_one(db_pool)
+    .await?;
+    Ok(in_use)
+}
+
+fn resize(bytes: &[u8]) -> Result<Vec<(u32, Vec<u8>)>, ImageError> {
+    let reader = || Reader::new(Cursor::new(bytes)).with_guessed_format();
+    let (width, height) = reader()?.into_dimensions()?;
+    if width as u64 * height as u64 > MAX_PIXELS {
+        return Err(ImageError::Limits(LimitError::from_kind(
+            LimitErrorKind::DimensionError,
+        )));
+    }
+    let image = reader()?.decode()?;
+    let (width, height) = (image.width(), image.height());
+    let side = width.min(height);
+    // JPEG has no alpha channel.
+    let square = DynamicImage::ImageRgb8(
+        image
+            .crop_imm((width - side) / 2, (height - side) / 2, side, side)
+            .to_rgb8(),
+    );
+
+    SIZES
+        .iter()
+        .map(|&size| {
+            let mut encoded = Vec::new();
+            square
+                .resize_exact(size, size, FilterType::Lanczos3)
+                .write_to(
+                    &mut encoded,
+                    ImageOutputFormat::Jpeg(JPEG_QUALITY),
+                )?;
+            Ok((size, encoded))
+        })
+        .collect()
+}
+
+/// Replaces the caller's avatar with the `image` field of a multipart form.
+pub async fn upload(
+    user: CurrentUser,
+    db_pool: web::Data<PgPool>,
+    settings: web::Data<Settings>,
+    mut payload: Multipart,
+) -> Result<HttpResponse, ServerError> {
+    let mut upload = None;
+    while let Ok(Some(mut field)) = payload.try_next().await {
+        let is_image = field
+            .content_disposition()
+            .and_then(|cd| cd.get_name().map(|name| name == "image"))
+            .unwrap_or(false);
+        if is_image {
+            upload = Some(media::read_image(&mut field, &settings).await?);
+        }
+    }
+    let upload = upload.ok_or(ServerError::ValidationError {
+        field: "image",
+        message: "Image not present".into(),
+    })?;
+
+    // Decoding and resizing is slow enough to keep off the event loop.
+    let timer = metrics::MEDIA_PROCESSING_DURATION
+        .with_label_values(&[MediaType::Image.as_str()])
+        .start_timer();
+    let resized = web::block(move || resize(&upload.bytes)).await;
+    timer.observe_duration();
+    let resized = resized.map_err(|e| match e {
+        BlockingError::Error(e) => ServerError::ValidationError {
+            field: "image",
+            message: e.to_string(),
+        },
+        e => ServerError::FileSystemError(e.to_string()),
+    })?;
+
+    let stem = Uuid::new_v4().to_simple().to_string();
+    let mut written = media::PendingFiles::new(&settings.storage_root);
+    for (size, bytes) in resized {
+        written.write_named(filename(&stem, size), bytes).await?;
+    }
+
+    let mut tx = db_pool.begin().await?;
+    let (previous,): (Option<String>,) = sqlx::query_as(
+        "SELECT avatar_filename FROM app_user WHERE id = $1 FOR UPDATE",
+    )
+    .bind(user.0)
+    .fetch_optional(&mut tx)
+    .await?
+    .ok_or(ServerError::Unauthorized)?;
+    sqlx::query("UPDATE app_user SET avatar_filename = $2 WHERE id = $1")
+        .bind(user.0)
+        .bind(&stem)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    written.keep();
+
+    if let Some(previous) = previous {
+        media::remove_files(&settings.storage_root, filenames(&previous))
+            .await?;
+    }
+    Ok(HttpResponse::Ok().json(Avatar::new(&stem)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    #[test]
+    fn resizes_to_every_size() {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(300, 200))
+            .write_to(&mut png, ImageOutputFormat::Png)
+            .unwrap();
+        let resized = resize(&png).unwrap();
+        assert_eq!(resized.len(), SIZES.len());
+        for (size, jpeg) in resized {
+            let image = image::load_from_memory(&jpeg).unwrap();
+            assert_eq!(image.dimensions(), (size, size));
+        }
+    }
+
+    #[test]
+    fn rejects_too_many_pixels_before_decoding() {
+        // Just a BMP header claiming 20000x20000, no pixels.
+        let mut bmp = b"BM".to_vec();
+        for field in [0u32, 0, 54, 40, 20_000, 20_000] {
+            bmp.extend_from_slice(&field.to_le_bytes());
+        }
+        bmp.extend_from_slice(&1u16.to_le_bytes());
+        bmp.extend_from_slice(&24u16.to_le_bytes());
+        bmp.extend_from_slice(&[0; 24]);
+        assert!(matches!(resize(&bmp), Err(ImageError::Limits(_))));
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(resize(b"not an image").is_err());
+    }
+
+    #[test]
+    fn avatar_sizes() {
+        assert_eq!(stem_of("0a1b2c-64.jpg"), Some("0a1b2c"));
+        assert_eq!(stem_of("0a1b2c-256.jpg"), Some("0a1b2c"));
+    }
+
+    #[test]
+    fn other_files() {
+        for name in [
+            "0a1b2c.jpg",
+            "0a1b2c-128.jpg",
+            "0a1b2c-64.png",
+            "-64.jpg",
+            "xyz-64.jpg",
+            "../0a1b2c-64.jpg",
+            "0a1b2c-64-64.jpg",
+        ] {
+            assert_eq!(stem_of(name), None, "{}", name);
+        }
+    }
+}
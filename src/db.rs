@@ -0,0 +1,93 @@
+//! The connection pool, configured from `Settings`.
+
+use crate::{settings::Settings, ServerSetupError};
+use actix_web::rt::time::delay_for;
+use serde::Serialize;
+use sqlx::{
+    postgres::{PgPool, PgPoolOptions},
+    Executor,
+};
+use std::time::{Duration, Instant};
+
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(250);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Pool options from the settings. Every connection gets the configured
+/// `statement_timeout` as it's opened.
+pub fn pool_options(settings: &Settings) -> PgPoolOptions {
+    let statement_timeout = settings.statement_timeout_ms;
+    PgPoolOptions::new()
+        .max_connections(settings.pool_size)
+        .min_connections(settings.pool_min_connections)
+        .connect_timeout(Duration::from_secs(
+            settings.pool_connect_timeout_seconds,
+        ))
+        .idle_timeout(Duration::from_secs(settings.pool_idle_timeout_seconds))
+        .after_connect(move |conn| {
+            Box::pin(async move {
+                conn.execute(
+                    format!("SET statement_timeout = {}", statement_timeout)
+                        .as_str(),
+                )
+                .await?;
+                Ok(())
+            })
+        })
+}
+
+/// Connects, retrying with exponential backoff while the database can't be
+/// reached or is still starting up, for up to `db_startup_timeout_seconds`.
+/// Anything else, like bad credentials, fails straight away.
+pub async fn connect(settings: &Settings) -> Result<PgPool, ServerSetupError> {
+    let give_up_at = Instant::now()
+        + Duration::from_secs(settings.db_startup_timeout_seconds);
+    let mut delay = FIRST_RETRY_DELAY;
+    loop {
+        match pool_options(settings).connect(&settings.database_uri).await {
+            Ok(pool) => return Ok(pool),
+            Err(e)
+                if is_unreachable(&e)
+                    && Instant::now() + delay < give_up_at =>
+            {
+                log::warn!(
+                    "database not reachable ({}), retrying in {:?}",
+                    e,
+                    delay
+                );
+                delay_for(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Postgres answering "the database system is starting up", as it does for
+/// a while after the container is.
+const CANNOT_CONNECT_NOW: &str = "57P03";
+
+fn is_unreachable(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut => true,
+        sqlx::Error::Database(e) => {
+            e.code().as_deref() == Some(CANNOT_CONNECT_NOW)
+        }
+        _ => false,
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PoolStats {
+    pub size: u32,
+    pub idle: usize,
+    pub max: u32,
+}
+
+pub fn stats(db_pool: &PgPool, settings: &Settings) -> PoolStats {
+    PoolStats {
+        size: db_pool.size(),
+        idle: db_pool.num_idle(),
+        max: settings.pool_size,
+    }
+}
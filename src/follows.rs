@@ -0,0 +1,196 @@
+//! Following other users, and the feed of what they've written.
+
+use crate::{
+    auth::CurrentUser,
+    pagination::{Page, PageQuery},
+    public_id,
+    users::{self, UserSummary},
+    ServerError,
+};
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+use uuid::Uuid;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FollowData {
+    user_id: i32,
+    following: bool,
+}
+
+pub async fn follow(
+    user: CurrentUser,
+    db_pool: web::Data<PgPool>,
+    web::Path(id): web::Path<i32>,
+) -> Result<HttpResponse, ServerError> {
+    if id == user.0 {
+        return Err(ServerError::ValidationError {
+            field: "id",
+            message: "You can't follow yourself".into(),
+        });
+    }
+    if !users::exists(&db_pool, id).await? {
+        return Err(ServerError::NotFound);
+    }
+
+    // Following twice is the same as following once.
+    sqlx::query(
+        r#"
+INSERT INTO follow (follower_id, followed_id)
+VALUES ($1, $2)
+ON CONFLICT DO NOTHING
+"#,
+    )
+    .bind(user.0)
+    .bind(id)
+    .execute(&**db_pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(FollowData {
+        user_id: id,
+        following: true,
+    }))
+}
+
+pub async fn unfollow(
+    user: CurrentUser,
+    db_pool: web::Data<PgPool>,
+    web::Path(id): web::Path<i32>,
+) -> Result<HttpResponse, ServerError> {
+    sqlx::query(
+        "DELETE FROM follow WHERE follower_id = $1 AND followed_id = $2",
+    )
+    .bind(user.0)
+    .bind(id)
+    .execute(&**db_pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(FollowData {
+        user_id: id,
+        following: false,
+    }))
+}
+
+/// Who follows the user, most recent first.
+pub async fn followers(
+    db_pool: web::Data<PgPool>,
+    web::Path(id): web::Path<i32>,
+    page: web::Query<PageQuery>,
+) -> Result<HttpResponse, ServerError> {
+    let query = r#"
+SELECT app_user.id, app_user.display_name, app_user.avatar_filename
+FROM follow, app_user
+WHERE follow.followed_id = $1
+AND app_user.id = follow.follower_id
+ORDER BY follow.created_at DESC, app_user.id
+LIMIT $2 OFFSET $3
+"#;
+    Ok(HttpResponse::Ok().json(list(&db_pool, id, &page, query).await?))
+}
+
+/// Who the user follows, most recent first.
+pub async fn following(
+    db_pool: web::Data<PgPool>,
+    web::Path(id): web::Path<i32>,
+    page: web::Query<PageQuery>,
+) -> Result<HttpResponse, ServerError> {
+    let query = r#"
+SELECT app_user.id, app_user.display_name, app_user.avatar_filename
+FROM follow, app_user
+WHERE follow.follower_id = $1
+AND app_user.id = follow.followed_id
+ORDER BY follow.created_at DESC, app_user.id
+LIMIT $2 OFFSET $3
+"#;
+    Ok(HttpResponse::Ok().json(list(&db_pool, id, &page, query).await?))
+}
+
+async fn list(
+    db_pool: &PgPool,
+    id: i32,
+    page: &PageQuery,
+    query: &str,
+) -> Result<Page<UserSummary>, ServerError> {
+    // An empty list would look the same as a user nobody follows.
+    if !users::exists(db_pool, id).await? {
+        return Err(ServerError::NotFound);
+    }
+    let users: Vec<UserSummary> = sqlx::query_as(query)
+        .bind(id)
+        .bind(page.fetch_limit())
+        .bind(page.offset())
+        .fetch_all(db_pool)
+        .await?;
+    Ok(page.page(users))
+}
+
+#[derive(sqlx::FromRow)]
+struct FeedRow {
+    public_id: Uuid,
+    title: String,
+    published_at: DateTime<Utc>,
+    author_id: i32,
+    author_display_name: String,
+    author_avatar_filename: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FeedItem {
+    id: Uuid,
+    path: String,
+    title: String,
+    published_at: DateTime<Utc>,
+    author: UserSummary,
+}
+
+/// Published, public how-tos by the users the caller follows, most recently
+/// published first.
+pub async fn feed(
+    user: CurrentUser,
+    db_pool: web::Data<PgPool>,
+    page: web::Query<PageQuery>,
+) -> Result<HttpResponse, ServerError> {
+    let rows: Vec<FeedRow> = sqlx::query_as(
+        r#"
+SELECT
+    howto.public_id,
+    howto.title,
+    howto.published_at,
+    app_user.id AS author_id,
+    app_user.display_name AS author_display_name,
+    app_user.avatar_filename AS author_avatar_filename
+FROM howto, follow, app_user
+WHERE follow.follower_id = $1
+AND howto.author_id = follow.followed_id
+AND app_user.id = howto.author_id
+AND howto.visibility = 'public'
+AND howto.published_at IS NOT NULL
+ORDER BY howto.published_at DESC, howto.id DESC
+LIMIT $2 OFFSET $3
+"#,
+    )
+    .bind(user.0)
+    .bind(page.fetch_limit())
+    .bind(page.offset())
+    .fetch_all(&**db_pool)
+    .await?;
+
+    let items = rows
+        .into_iter()
+        .map(|row| FeedItem {
+            id: row.public_id,
+            path: public_id::path(&row.title, row.public_id),
+            title: row.title,
+            published_at: row.published_at,
+            author: UserSummary {
+                id: row.author_id,
+                display_name: row.author_display_name,
+                avatar_filename: row.author_avatar_filename,
+            },
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(page.page(items)))
+}
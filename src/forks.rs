@@ -0,0 +1,142 @@
+//! Starting a new how-to from a copy of an existing one. The fork gets its
+//! own steps, but shares media files with the how-to it came from, so files
+//! are only removed once no step anywhere uses them.
+
+use crate::{
+    auth::CurrentUser,
+    how_to_props,
+    public_id::{self, PublicId},
+    revisions, visibility, ServerError,
+};
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+use uuid::Uuid;
+
+/// The how-to a fork was made from, for "forked from".
+#[derive(Debug, Serialize)]
+pub struct Origin {
+    #[serde(rename = "id")]
+    public_id: Uuid,
+    path: String,
+    title: String,
+}
+
+/// Where `how_to_id` was forked from, if it was and everyone can still read
+/// it. A private or unpublished origin isn't given away.
+pub async fn origin(
+    db_pool: &PgPool,
+    how_to_id: i32,
+) -> Result<Option<Origin>, ServerError> {
+    let origin: Option<(Uuid, String)> = sqlx::query_as(
+        r#"
+SELECT origin.public_id, origin.title
+FROM howto, howto AS origin
+WHERE howto.id = $1
+AND origin.id = howto.forked_from_id
+AND origin.visibility <> 'private'
+AND origin.published_at IS NOT NULL
+"#,
+    )
+    .bind(how_to_id)
+    .fetch_optional(db_pool)
+    .await?;
+    Ok(origin.map(|(public_id, title)| Origin {
+        path: public_id::path(&title, public_id),
+        public_id,
+        title,
+    }))
+}
+
+#[derive(sqlx::FromRow)]
+struct OriginStep {
+    id: i32,
+    title: String,
+    seconds: i32,
+    position: i32,
+}
+
+/// Copies a how-to the caller can read into a new private draft of theirs,
+/// with the same title, steps, order and media.
+pub async fn fork(
+    user: CurrentUser,
+    db_pool: web::Data<PgPool>,
+    web::Path(public_id): web::Path<PublicId>,
+) -> Result<HttpResponse, ServerError> {
+    let origin_id = public_id::resolve(&db_pool, public_id).await?;
+    visibility::check(&db_pool, origin_id, Some(&user)).await?;
+
+    let mut tx = db_pool.begin().await?;
+    let (id,): (i32,) = sqlx::query_as(
+        r#"
+INSERT INTO howto (title, author_id, visibility, forked_from_id)
+SELECT title, $2, 'private', id
+FROM howto
+WHERE id = $1
+RETURNING id
+"#,
+    )
+    .bind(origin_id)
+    .bind(user.0)
+    .fetch_one(&mut tx)
+    .await?;
+
+    let steps: Vec<OriginStep> = sqlx::query_as(
+        r#"
+SELECT step.id, step.title, step.seconds, howto_step.position
+FROM step, howto_step
+WHERE howto_step.howto_id = $1
+AND howto_step.step_id = step.id
+ORDER BY howto_step.position, step.id
+"#,
+    )
+    .bind(origin_id)
+    .fetch_all(&mut tx)
+    .await?;
+
+    for step in steps {
+        let (step_id,): (i32,) = sqlx::query_as(
+            "INSERT INTO step (title, seconds) VALUES ($1, $2) RETURNING id",
+        )
+        .bind(&step.title)
+        .bind(step.seconds)
+        .fetch_one(&mut tx)
+        .await?;
+        sqlx::query(
+            r#"
+INSERT INTO howto_step (howto_id, step_id, position)
+VALUES ($1, $2, $3)
+"#,
+        )
+        .bind(id)
+        .bind(step_id)
+        .bind(step.position)
+        .execute(&mut tx)
+        .await?;
+        // Same files, new rows.
+        sqlx::query(
+            r#"
+INSERT INTO step_media (step_id, position, filename, media_type)
+SELECT $2, position, filename, media_type
+FROM step_media
+WHERE step_id = $1
+"#,
+        )
+        .bind(step.id)
+        .bind(step_id)
+        .execute(&mut tx)
+        .await?;
+    }
+
+    revisions::record(
+        &mut tx,
+        id,
+        Some(user.0),
+        revisions::Change::Create,
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(how_to_props(&db_pool, id).await?))
+}
@@ -0,0 +1,99 @@
+//! Probes for the process, its dependencies, and what build is running.
+
+use crate::{db, migrations, settings::Settings};
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+use std::fs;
+
+/// The process is up and answering requests.
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(Status { status: "ok" })
+}
+
+#[derive(Serialize)]
+struct Status {
+    status: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Readiness {
+    database: bool,
+    migrations: bool,
+    storage: bool,
+    /// Informational; a busy pool doesn't make the instance unready.
+    pool: db::PoolStats,
+}
+
+/// Everything a request might need is available. Answers 503 otherwise, with
+/// which check failed.
+pub async fn readyz(
+    db_pool: web::Data<PgPool>,
+    settings: web::Data<Settings>,
+) -> HttpResponse {
+    let applied = applied_migration(&db_pool).await;
+    let readiness = Readiness {
+        database: applied.is_ok(),
+        migrations: matches!(applied, Ok(v) if v == migrations::latest()),
+        storage: storage_writable(&settings).await,
+        pool: db::stats(&db_pool, &settings),
+    };
+
+    if readiness.database && readiness.migrations && readiness.storage {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Version {
+    git_sha: &'static str,
+    build_time: String,
+    /// Latest migration applied to the database, if it could be read.
+    migration_version: Option<i64>,
+    /// Latest migration this build knows about.
+    latest_migration: Option<i64>,
+}
+
+pub async fn version(db_pool: web::Data<PgPool>) -> HttpResponse {
+    let build_time = env!("BUILD_TIMESTAMP")
+        .parse()
+        .map(|secs| {
+            chrono::DateTime::<chrono::Utc>::from_utc(
+                chrono::NaiveDateTime::from_timestamp(secs, 0),
+                chrono::Utc,
+            )
+            .to_rfc3339()
+        })
+        .unwrap_or_default();
+
+    HttpResponse::Ok().json(Version {
+        git_sha: env!("GIT_SHA"),
+        build_time,
+        migration_version: applied_migration(&db_pool).await.ok().flatten(),
+        latest_migration: migrations::latest(),
+    })
+}
+
+async fn applied_migration(
+    db_pool: &PgPool,
+) -> Result<Option<i64>, sqlx::Error> {
+    let (version,): (Option<i32>,) =
+        sqlx::query_as("SELECT MAX(version) FROM refinery_schema_history")
+            .fetch_one(db_pool)
+            .await?;
+    Ok(version.map(i64::from))
+}
+
+async fn storage_writable(settings: &Settings) -> bool {
+    let probe = settings.storage_root.join(".readyz");
+    web::block(move || {
+        fs::write(&probe, b"ok")?;
+        fs::remove_file(&probe)
+    })
+    .await
+    .is_ok()
+}
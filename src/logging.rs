@@ -0,0 +1,252 @@
+//! JSON logs, one object per line, to stdout or syslog. Each request gets an
+//! id, which is logged with it, sent back in `X-Request-Id` and included in
+//! error responses so a user's report can be matched to the log.
+
+use crate::{
+    settings::{LogOutput, Settings},
+    ServerError, ServerSetupError,
+};
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::{
+        header::{HeaderName, HeaderValue},
+        HeaderMap,
+    },
+    Error, HttpResponse, ResponseError,
+};
+use futures::future::{FutureExt, LocalBoxFuture};
+use lazy_static::lazy_static;
+use log::{Level, Log, Metadata, Record};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::{io::Write, sync::Mutex, time::Instant};
+use uuid::Uuid;
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Header values that never make it into the log.
+const REDACTED_HEADERS: &[&str] = &[
+    "authorization",
+    "cookie",
+    "proxy-authorization",
+    "set-cookie",
+];
+
+type Syslog = syslog::Logger<syslog::LoggerBackend, syslog::Formatter3164>;
+
+enum Sink {
+    Stdout,
+    Syslog(Syslog),
+}
+
+lazy_static! {
+    // Shared by the `log` records and the request log.
+    static ref SINK: Mutex<Sink> = Mutex::new(Sink::Stdout);
+}
+
+struct JsonLogger {
+    filter: env_logger::filter::Filter,
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let mut fields = Map::new();
+        fields.insert("message".into(), record.args().to_string().into());
+        write(record.level(), record.target(), fields);
+    }
+
+    fn flush(&self) {
+        if let Sink::Stdout = *SINK.lock().unwrap() {
+            let _ = std::io::stdout().flush();
+        }
+    }
+}
+
+pub fn init(settings: &Settings) -> Result<(), ServerSetupError> {
+    if let LogOutput::Syslog = settings.log_output {
+        let formatter = syslog::Formatter3164 {
+            facility: syslog::Facility::LOG_USER,
+            hostname: None,
+            process: "howido".into(),
+            pid: std::process::id() as i32,
+        };
+        // on mac, run: `tail -f /var/log/system.log` to see system log
+        let logger = syslog::unix(formatter)
+            .map_err(|e| ServerSetupError::Logging(e.to_string()))?;
+        *SINK.lock().unwrap() = Sink::Syslog(logger);
+    }
+
+    let filter = env_logger::filter::Builder::new()
+        .parse(&settings.log_level)
+        .build();
+    log::set_max_level(filter.filter());
+    log::set_boxed_logger(Box::new(JsonLogger { filter }))
+        .map_err(|e| ServerSetupError::Logging(e.to_string()))
+}
+
+fn write(level: Level, target: &str, mut fields: Map<String, Value>) {
+    fields.insert("timestamp".into(), chrono::Utc::now().to_rfc3339().into());
+    fields.insert("level".into(), level.as_str().into());
+    fields.insert("target".into(), target.into());
+    let line = Value::Object(fields).to_string();
+
+    match &mut *SINK.lock().unwrap() {
+        Sink::Stdout => {
+            let _ = writeln!(std::io::stdout().lock(), "{}", line);
+        }
+        Sink::Syslog(logger) => {
+            let _ = match level {
+                Level::Error => logger.err(line),
+                Level::Warn => logger.warning(line),
+                Level::Info => logger.info(line),
+                Level::Debug | Level::Trace => logger.debug(line),
+            };
+        }
+    }
+}
+
+/// Set on a request once we know who made it.
+pub struct UserId(pub i32);
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ErrorBody<'a> {
+    error: &'a ServerError,
+    request_id: &'a str,
+}
+
+/// Tags each request with an id and writes one log line per response.
+/// Reuses the caller's `X-Request-Id` if it's sensible, so ids can be
+/// followed through a proxy.
+pub fn log_requests<S>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> LocalBoxFuture<'static, Result<ServiceResponse, Error>>
+where
+    S: Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse,
+        Error = Error,
+    >,
+    S::Future: 'static,
+{
+    let start = Instant::now();
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| {
+            !value.is_empty()
+                && value.len() <= 64
+                && value.bytes().all(|b| {
+                    b.is_ascii_alphanumeric() || b == b'-' || b == b'_'
+                })
+        })
+        .map(String::from)
+        .unwrap_or_else(|| Uuid::new_v4().to_simple().to_string());
+
+    let method = req.method().to_string();
+    let path = req.path().to_string();
+    let headers = redacted(req.headers());
+    let response = srv.call(req);
+
+    async move {
+        let mut response = response.await?;
+
+        let server_error = response
+            .response()
+            .error()
+            .and_then(|e| e.as_error::<ServerError>());
+        let error_variant = server_error.map(|e| e.variant_name());
+        let error_response = server_error.map(|e| {
+            HttpResponse::build(e.status_code()).json(ErrorBody {
+                error: e,
+                request_id: &id,
+            })
+        });
+        if let Some(error_response) = error_response {
+            response = response.into_response(error_response);
+        }
+        if let Ok(value) = HeaderValue::from_str(&id) {
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+        }
+
+        let status = response.status();
+        let level = if status.is_server_error() {
+            Level::Error
+        } else {
+            Level::Info
+        };
+        if log::log_enabled!(target: "request", level) {
+            let request = response.request();
+            let user_id = request.extensions().get::<UserId>().map(|u| u.0);
+            let route = request.match_pattern();
+            let fields = json!({
+                "requestId": id,
+                "method": method,
+                "route": route,
+                "path": path,
+                "status": status.as_u16(),
+                "latencyMs": start.elapsed().as_secs_f64() * 1000.0,
+                "userId": user_id,
+                "error": error_variant,
+                "headers": headers,
+            });
+            if let Value::Object(fields) = fields {
+                write(level, "request", fields);
+            }
+        }
+        Ok(response)
+    }
+    .boxed_local()
+}
+
+fn redacted(headers: &HeaderMap) -> Map<String, Value> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if REDACTED_HEADERS.contains(&name.as_str()) {
+                "[redacted]".into()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.as_str().to_string(), value.into())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_credentials() {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("authorization", "Bearer secret"),
+            ("cookie", "auth=secret"),
+            ("set-cookie", "auth=secret"),
+            ("user-agent", "curl/7.68.0"),
+        ] {
+            headers.insert(
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            );
+        }
+        let logged = redacted(&headers);
+        assert_eq!(logged["authorization"], "[redacted]");
+        assert_eq!(logged["cookie"], "[redacted]");
+        assert_eq!(logged["set-cookie"], "[redacted]");
+        assert_eq!(logged["user-agent"], "curl/7.68.0");
+        assert!(!Value::Object(logged).to_string().contains("secret"));
+    }
+}
@@ -0,0 +1,121 @@
+//! Sending account emails. Handlers take a `web::Data<dyn Mailer>`, which is
+//! SMTP in production and the log or a directory of files otherwise.
+
+use crate::{
+    settings::{MailTransport, Settings},
+    ServerSetupError,
+};
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, Message,
+    SmtpTransport, Transport,
+};
+use std::{fs, path::PathBuf, sync::Arc};
+use uuid::Uuid;
+
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Sending blocks, so call it from `web::block`.
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: &Email) -> Result<(), String>;
+}
+
+pub fn mailer(
+    settings: &Settings,
+) -> Result<Arc<dyn Mailer>, ServerSetupError> {
+    let invalid = |name, message: String| ServerSetupError::InvalidSetting {
+        name,
+        message,
+    };
+    Ok(match settings.mail_transport {
+        MailTransport::Console => Arc::new(ConsoleMailer),
+        MailTransport::File => {
+            fs::create_dir_all(&settings.mail_dir)
+                .map_err(|e| invalid("mail_dir", e.to_string()))?;
+            Arc::new(FileMailer {
+                dir: settings.mail_dir.clone(),
+            })
+        }
+        MailTransport::Smtp => {
+            let from = settings
+                .mail_from
+                .parse()
+                .map_err(|e| invalid("mail_from", format!("{}", e)))?;
+            let host = settings.smtp_host.as_deref().unwrap_or_default();
+            let mut transport = SmtpTransport::starttls_relay(host)
+                .map_err(|e| invalid("smtp_host", e.to_string()))?
+                .port(settings.smtp_port);
+            if let (Some(username), Some(password)) =
+                (&settings.smtp_username, &settings.smtp_password)
+            {
+                transport = transport.credentials(Credentials::new(
+                    username.clone(),
+                    password.clone(),
+                ));
+            }
+            Arc::new(SmtpMailer {
+                transport: transport.build(),
+                from,
+            })
+        }
+    })
+}
+
+struct SmtpMailer {
+    transport: SmtpTransport,
+    from: Mailbox,
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: &Email) -> Result<(), String> {
+        let to: Mailbox = email.to.parse().map_err(|e| format!("{}", e))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(&email.subject)
+            .body(email.body.clone())
+            .map_err(|e| e.to_string())?;
+        self.transport
+            .send(&message)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+struct ConsoleMailer;
+
+impl Mailer for ConsoleMailer {
+    fn send(&self, email: &Email) -> Result<(), String> {
+        log::info!(
+            target: "mail",
+            "to: {}, subject: {}\n{}",
+            email.to,
+            email.subject,
+            email.body
+        );
+        Ok(())
+    }
+}
+
+/// One file per email, named so they sort in the order they were sent.
+struct FileMailer {
+    dir: PathBuf,
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, email: &Email) -> Result<(), String> {
+        let name = format!(
+            "{}-{}.txt",
+            chrono::Utc::now().format("%Y%m%dT%H%M%S%.6f"),
+            Uuid::new_v4().to_simple()
+        );
+        let contents = format!(
+            "To: {}\nSubject: {}\n\n{}\n",
+            email.to, email.subject, email.body
+        );
+        fs::write(self.dir.join(name), contents).map_err(|e| e.to_string())
+    }
+}
@@ -1,35 +1,43 @@
 use actix_cors::Cors;
-use actix_files::Files;
 use actix_multipart::Multipart;
 use actix_web::{
-    http, middleware, web, App, HttpResponse, HttpServer, Responder,
-    ResponseError,
+    http, middleware, web, App, HttpRequest, HttpResponse, HttpServer,
+    Responder, ResponseError,
 };
 use dotenv::dotenv;
-use env::VarError;
-use futures::{StreamExt, TryStreamExt};
-use log;
-use refinery::{self, config::Config};
+use futures::TryStreamExt;
+use public_id::PublicId;
 use serde::{Deserialize, Serialize};
-use sqlx;
-use sqlx::postgres::{PgPool, PgPoolOptions};
-use std::{env, io::Write};
-use syslog;
-
-// #[macro_use]
-// extern crate lazy_static;
-
-// let x = include_str!("../client/build/index.html");
-// let x = include_str!("../client/build/dist/index.js");
-// const CSS: &'static str = include_str!("../client/build/dist/index.css");
-// use sha1::{Digest, Sha1};
-
-// lazy_static! {
-//     static ref CSS_HASH: &'static str = {
-//         let _hasher = Sha1::digest(CSS.as_bytes());
-//         ""
-//     };
-// }
+use settings::{Environment, Settings};
+use sqlx::postgres::PgPool;
+use std::{env::VarError, path::PathBuf, time::Duration};
+use structopt::StructOpt;
+use uuid::Uuid;
+
+mod accounts;
+mod assets;
+mod auth;
+mod avatars;
+mod cli;
+mod db;
+mod follows;
+mod forks;
+mod health;
+mod logging;
+mod mail;
+mod media;
+mod metrics;
+mod migrations;
+mod pagination;
+mod public_id;
+mod publishing;
+mod revisions;
+mod settings;
+mod tls;
+mod users;
+mod versions;
+mod video;
+mod visibility;
 
 // This type is reflected on client.
 #[derive(Debug, Serialize)]
@@ -41,6 +49,14 @@ pub enum ServerError {
         field: &'static str,
         message: String,
     },
+    NotFound,
+    Unauthorized,
+    Forbidden,
+    /// The how-to changed since the version the edit was made against.
+    /// Carries it as it is now.
+    Conflict(Box<HowToPageProps>),
+    /// An edit that didn't say which version it was made against.
+    PreconditionRequired,
 }
 
 // pub struct InputError {
@@ -61,7 +77,42 @@ impl std::fmt::Display for ServerError {
     }
 }
 
-impl ResponseError for ServerError {}
+impl ResponseError for ServerError {
+    fn status_code(&self) -> http::StatusCode {
+        match self {
+            ServerError::NotFound => http::StatusCode::NOT_FOUND,
+            ServerError::Unauthorized => http::StatusCode::UNAUTHORIZED,
+            ServerError::Forbidden => http::StatusCode::FORBIDDEN,
+            ServerError::Conflict(_) => http::StatusCode::CONFLICT,
+            ServerError::PreconditionRequired => {
+                http::StatusCode::PRECONDITION_REQUIRED
+            }
+            _ => http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        metrics::SERVER_ERRORS
+            .with_label_values(&[self.variant_name()])
+            .inc();
+        HttpResponse::build(self.status_code()).json(self)
+    }
+}
+
+impl ServerError {
+    fn variant_name(&self) -> &'static str {
+        match self {
+            ServerError::DatabaseError(_) => "DatabaseError",
+            ServerError::FileSystemError(_) => "FileSystemError",
+            ServerError::ValidationError { .. } => "ValidationError",
+            ServerError::NotFound => "NotFound",
+            ServerError::Unauthorized => "Unauthorized",
+            ServerError::Forbidden => "Forbidden",
+            ServerError::Conflict(_) => "Conflict",
+            ServerError::PreconditionRequired => "PreconditionRequired",
+        }
+    }
+}
 
 impl From<sqlx::Error> for ServerError {
     fn from(error: sqlx::Error) -> Self {
@@ -69,9 +120,18 @@ impl From<sqlx::Error> for ServerError {
     }
 }
 
+// The fields are only read through `Debug`, when `main` returns the error.
+#[allow(dead_code)]
 #[derive(Debug)]
 enum ServerSetupError {
     ReadEnvironmentVariable(std::env::VarError),
+    ReadConfigFile(PathBuf, std::io::Error),
+    ParseConfigFile(PathBuf, toml::de::Error),
+    InvalidSetting { name: &'static str, message: String },
+    LoadCertificate { path: PathBuf, message: String },
+    Logging(String),
+    Command(String),
+    Migration(String),
     DatabaseSetup(sqlx::Error),
     ServerStart(std::io::Error),
 }
@@ -91,11 +151,6 @@ impl From<std::io::Error> for ServerSetupError {
     }
 }
 
-mod embeded {
-    use refinery::embed_migrations;
-    embed_migrations!("./src/sql_migrations");
-}
-
 // What does this look like when "desugared?"
 #[actix_web::main]
 async fn main() -> Result<(), ServerSetupError> {
@@ -103,102 +158,217 @@ async fn main() -> Result<(), ServerSetupError> {
     // [environment variables should be ]
     dotenv().ok();
 
-    let db_uri: String = env::var("DATABASE_URI")?;
-    let port: String = env::var("PORT")?;
-    let mut conn = Config::from_env_var("DATABASE_URI").unwrap();
-    embeded::migrations::runner().run(&mut conn).unwrap();
+    let opt = cli::Opt::from_args();
+    let settings = Settings::load()?;
+    logging::init(&settings)?;
 
-    // setup directory for images
-    use std::fs;
-    fs::create_dir_all("./tmp/")
-        .expect("failed to setup tmp directory for images");
-
-    let pool = PgPoolOptions::new().connect(&db_uri).await?;
-
-    std::env::set_var("RUST_LOG", "actix_web=info,debug");
-
-    // What is this magic?
-    env_logger::init();
-    // on mac, run: `tail -f /var/log/system.log` to see system log
+    let command = opt
+        .command
+        .unwrap_or(cli::Command::Serve { no_migrate: false });
+    match command {
+        cli::Command::Serve { no_migrate } => serve(settings, no_migrate).await,
+        command => cli::run(command, settings).await,
+    }
+}
 
-    // maybe log in JSON? then tools can pull the JSON. Makes enough sense, because the API is JSON
-    // syslog::init(
-    //     syslog::Facility::LOG_SYSLOG,
-    //     log::LevelFilter::Debug,
-    //     Some("How I Do"),
-    // )
-    // .expect("failed to setup logging");
+async fn serve(
+    settings: Settings,
+    no_migrate: bool,
+) -> Result<(), ServerSetupError> {
+    // setup directory for images
+    let storage_error = |e: std::io::Error| ServerSetupError::InvalidSetting {
+        name: "storage_root",
+        message: e.to_string(),
+    };
+    std::fs::create_dir_all(&settings.storage_root).map_err(storage_error)?;
+    let removed = media::remove_partial_files(
+        &settings.storage_root,
+        Duration::from_secs(settings.shutdown_timeout_seconds),
+    )
+    .map_err(storage_error)?;
+    if removed > 0 {
+        log::warn!("removed {} half-written uploads", removed);
+    }
 
-    #[derive(Serialize)]
-    struct Hello {
-        msg: String,
+    // Before migrating, so a database that's still starting gets waited for.
+    let pool = db::connect(&settings).await?;
+
+    if !no_migrate {
+        migrations::run(&settings)?;
+    } else {
+        // Another instance is expected to migrate. Until it has, `/readyz`
+        // reports this one as not ready.
+        let applied = migrations::applied(&pool).await?;
+        let current = applied.last().map(|m| m.version as i64);
+        if current < migrations::latest() {
+            log::warn!(
+                "database schema is at V{}, this build expects V{}",
+                current.unwrap_or_default(),
+                migrations::latest().unwrap_or_default()
+            );
+        }
     }
-    HttpServer::new(move || {
-        // cors should be dependant on development mode. (Prod = no cors)
-        let cors = Cors::default()
-            .allowed_methods(vec!["GET", "POST", "DELETE", "PUT"])
-            .allow_any_origin();
-        App::new()
-            .wrap(cors)
-            .wrap(middleware::Logger::new(
-                r#"
-%r %s
-%b bytes (raw)
-%D ms
-%U
-%{cookie}i
 
-"#,
+    let bind_address = (settings.bind_address, settings.port);
+    let shutdown_timeout = settings.shutdown_timeout_seconds;
+    let server_pool = pool.clone();
+    let session_key = auth::session_key(&settings);
+    let mailer: web::Data<dyn mail::Mailer> =
+        web::Data::from(mail::mailer(&settings)?);
+    let tls_config = tls::server_config(&settings)?;
+    let redirect_server = match settings.http_redirect_port {
+        Some(port) => Some(tls::redirect_server(&settings, port)?),
+        None => None,
+    };
+    let server = HttpServer::new(move || {
+        App::new()
+            // Innermost, so it sees handler errors before they're turned
+            // into plain responses.
+            .wrap_fn(logging::log_requests)
+            .wrap(auth::identity_service(&settings, &session_key))
+            // Production serves the client itself, so it needs no CORS. With
+            // no allowed origins the middleware would refuse every write,
+            // since browsers send `Origin` on same-origin POSTs too.
+            .wrap(middleware::Condition::new(
+                settings.environment != Environment::Production,
+                cors(&settings),
             ))
             .wrap(middleware::Compress::new(
                 http::header::ContentEncoding::Gzip,
             ))
-            .data(pool.clone())
-            .route("/", web::get().to(index_html)) // should be the static web app for prod
-            .route("/dist/index.js", web::get().to(index_js)) // should be the static web app for prod
-            .route("/dist/index.css", web::get().to(index_css)) // should be the static web app for prod
+            .wrap_fn(metrics::track_requests)
+            .data(server_pool.clone())
+            .data(settings.clone())
+            .app_data(mailer.clone())
+            .route("/", web::get().to(assets::index_html))
             .route("/test-err", web::get().to(test_err))
+            .route("/healthz", web::get().to(health::healthz))
+            .route("/readyz", web::get().to(health::readyz))
+            .route("/version", web::get().to(health::version))
+            .route("/metrics", web::get().to(metrics::metrics))
             .service(
                 web::scope("/api")
-                    .service(
-                        Files::new("/images", "./tmp").show_files_listing(),
-                    )
-                    .default_service(web::to(|| {
-                        HttpResponse::Ok().json(Hello {
-                            msg: String::from("hello from the other side"),
-                        })
+                    .default_service(web::to(|| async {
+                        Err::<HttpResponse, _>(ServerError::NotFound)
                     }))
+                    .route("/sign-up", web::post().to(accounts::sign_up))
+                    .route(
+                        "/verify-email",
+                        web::post().to(accounts::verify_email),
+                    )
+                    .route(
+                        "/verify-email/resend",
+                        web::post().to(accounts::resend_verification),
+                    )
+                    .route(
+                        "/password-reset/request",
+                        web::post().to(accounts::request_password_reset),
+                    )
+                    .route(
+                        "/password-reset/confirm",
+                        web::post().to(accounts::confirm_password_reset),
+                    )
+                    .route("/login", web::post().to(auth::login))
+                    .route("/logout", web::post().to(auth::logout))
+                    .route("/feed", web::get().to(follows::feed))
+                    .route("/user/avatar", web::post().to(avatars::upload))
+                    .route("/user/{id}", web::get().to(users::profile))
+                    .route("/user/{id}/follow", web::post().to(follows::follow))
+                    .route(
+                        "/user/{id}/follow",
+                        web::delete().to(follows::unfollow),
+                    )
+                    .route(
+                        "/user/{id}/followers",
+                        web::get().to(follows::followers),
+                    )
+                    .route(
+                        "/user/{id}/following",
+                        web::get().to(follows::following),
+                    )
                     .route("/how-to", web::post().to(create_howto))
                     .route("/how-to", web::put().to(update_howto))
                     .route("/how-to/{id}", web::get().to(howto_page))
+                    .route("/how-to/{id}/fork", web::post().to(forks::fork))
+                    .route(
+                        "/how-to/{id}/publish",
+                        web::post().to(publishing::publish),
+                    )
+                    .route(
+                        "/how-to/{id}/visibility",
+                        web::put().to(visibility::set_visibility),
+                    )
+                    .route(
+                        "/how-to/{id}/revisions",
+                        web::get().to(revisions::list),
+                    )
+                    .route(
+                        "/how-to/{id}/revisions/diff",
+                        web::get().to(revisions::compare),
+                    )
+                    .route(
+                        "/how-to/{id}/revisions/{number}",
+                        web::get().to(revisions::get),
+                    )
+                    .route(
+                        "/how-to/{id}/revisions/{number}/restore",
+                        web::post().to(revisions::restore),
+                    )
                     .route("/step", web::post().to(create_step))
                     .route("/step/{id}", web::delete().to(delete_step))
                     .route("/step", web::put().to(update_step))
-                    .route("/img-upload", web::post().to(img_upload)),
+                    .route("/images/{filename}", web::get().to(media::serve))
+                    .route("/img-upload", web::post().to(img_upload))
+                    .route("/media-upload", web::post().to(img_upload)),
             )
-            .default_service(web::to(index_html))
+            .default_service(web::to(assets::serve))
     })
-    // .bind_rustls(port, config)? This is an error because of lib mismatch?
-    .bind(format!("0.0.0.0:{}", port))?
-    .run()
-    .await?;
+    // On SIGTERM/SIGINT, stop accepting connections and give in-flight
+    // requests this long to finish. Whatever is still running is dropped,
+    // which rolls back its transaction and removes its pending files.
+    .shutdown_timeout(shutdown_timeout);
+    let server = match tls_config {
+        Some(config) => server.bind_rustls(bind_address, config)?,
+        None => server.bind(bind_address)?,
+    }
+    .run();
+
+    let served = match redirect_server {
+        Some(redirect_server) => {
+            futures::try_join!(server, redirect_server).map(|_| ())
+        }
+        None => server.await,
+    };
+
+    log::info!("server stopped, closing database connections");
+    pool.close().await;
+    served?;
     Ok(())
 }
 
-async fn index_html() -> impl Responder {
-    let x = include_str!("../client/build/index.html");
-    // TODO: set eTag to the respective hash
-    HttpResponse::Ok().content_type("text/html").body(x)
-}
-async fn index_js() -> impl Responder {
-    let x = include_str!("../client/build/dist/index.js");
-    HttpResponse::Ok()
-        .content_type("application/javascript")
-        .body(x)
-}
-async fn index_css() -> impl Responder {
-    let x = include_str!("../client/build/dist/index.css");
-    HttpResponse::Ok().content_type("text/css").body(x)
+/// Staging allows a fixed list of origins, and development allows anything
+/// so the snowpack dev server can reach the API. Production doesn't use it.
+fn cors(settings: &Settings) -> Cors {
+    let mut cors = Cors::default()
+        .allowed_methods(vec!["GET", "POST", "DELETE", "PUT"])
+        // How-to versions, see `versions`.
+        .expose_headers(vec![http::header::ETAG]);
+    match settings.environment {
+        Environment::Development => {
+            cors = cors.allow_any_origin().allow_any_header();
+        }
+        Environment::Staging => {
+            for origin in &settings.cors_origins {
+                cors = cors.allowed_origin(origin);
+            }
+            cors = cors.allow_any_header();
+        }
+        Environment::Production => (),
+    }
+    if settings.cors_allow_credentials {
+        cors = cors.supports_credentials();
+    }
+    cors
 }
 
 mod hey {
@@ -213,34 +383,73 @@ async fn test_err() -> Result<HttpResponse, ServerError> {
     Err(ServerError::DatabaseError("This is a db error".into()))
 }
 
-#[derive(Serialize)]
+#[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct HowToPageProps {
+pub struct HowToPageProps {
     how_to: HowToDbRow,
-    steps: Vec<StepDbRow>,
+    /// The canonical path, with a slug of the title.
+    path: String,
+    author: Option<users::UserSummary>,
+    forked_from: Option<forks::Origin>,
+    steps: Vec<StepProps>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StepProps {
+    id: i32,
+    title: String,
+    seconds: i32,
+    media: Vec<media::StepMediaRow>,
 }
 
 async fn howto_page(
     db_pool: web::Data<PgPool>,
-    web::Path(id): web::Path<i32>,
+    web::Path(public_id): web::Path<PublicId>,
+    user: Option<auth::CurrentUser>,
 ) -> Result<HttpResponse, ServerError> {
+    let id = public_id::resolve(&db_pool, public_id).await?;
+    visibility::check(&db_pool, id, user.as_ref()).await?;
+    let props = how_to_props(&db_pool, id).await?;
+    Ok(HttpResponse::Ok()
+        .header(http::header::ETAG, versions::etag(props.how_to.version))
+        .json(props))
+}
+
+/// Everything the how-to page shows. Callers check it may be seen first.
+pub async fn how_to_props(
+    db_pool: &PgPool,
+    id: i32,
+) -> Result<HowToPageProps, ServerError> {
     // TODO: Do a transaction!
     // This is getting the how_to?
     let how_to_query = r#"
-SELECT id, title
+SELECT id, public_id, title, visibility, published_at, version
 FROM howto
 WHERE id = $1
 "#;
     let how_to: HowToDbRow = sqlx::query_as(how_to_query)
         .bind(id)
-        .fetch_one(&**db_pool)
+        .fetch_one(db_pool)
         .await?;
 
+    let author: Option<users::UserSummary> = sqlx::query_as(
+        r#"
+SELECT app_user.id, app_user.display_name, app_user.avatar_filename
+FROM howto, app_user
+WHERE howto.id = $1
+AND app_user.id = howto.author_id
+"#,
+    )
+    .bind(id)
+    .fetch_optional(db_pool)
+    .await?;
+
     let steps_query = r#"
 SELECT
     step.id,
     step.title,
-    step.image_filename
+    step.seconds
 FROM
     step,
     howto_step
@@ -253,19 +462,61 @@ ORDER BY step.id
 
     let steps: Vec<StepDbRow> = sqlx::query_as(steps_query)
         .bind(id)
-        .fetch_all(&**db_pool)
+        .fetch_all(db_pool)
+        .await?;
+
+    let media_query = r#"
+SELECT
+    step_media.step_id,
+    step_media.position,
+    step_media.filename,
+    step_media.media_type
+FROM
+    step_media,
+    howto_step
+WHERE
+    howto_step.howto_id = $1
+AND howto_step.step_id = step_media.step_id
+ORDER BY step_media.step_id, step_media.position
+"#;
+    let mut media: Vec<media::StepMediaRow> = sqlx::query_as(media_query)
+        .bind(id)
+        .fetch_all(db_pool)
         .await?;
 
-    Ok(HttpResponse::Ok().json(HowToPageProps { how_to, steps }))
+    let steps = steps
+        .into_iter()
+        .map(|step| {
+            let (step_media, rest) =
+                media.drain(..).partition(|m| m.step_id == step.id);
+            media = rest;
+            StepProps {
+                id: step.id,
+                title: step.title,
+                seconds: step.seconds,
+                media: step_media,
+            }
+        })
+        .collect();
+
+    let forked_from = forks::origin(db_pool, id).await?;
+
+    Ok(HowToPageProps {
+        path: public_id::path(&how_to.title, how_to.public_id),
+        how_to,
+        author,
+        forked_from,
+        steps,
+    })
 }
 
 async fn _delete_howto(
     db_pool: web::Data<PgPool>,
-    web::Path(id): web::Path<i32>,
+    web::Path(id): web::Path<PublicId>,
 ) -> impl Responder {
     let resp: Result<_, sqlx::Error> =
-        sqlx::query("DELETE FROM howto WHERE id = $1")
-            .bind(id)
+        sqlx::query("DELETE FROM howto WHERE public_id = $1")
+            .bind(id.0)
             .execute(&**db_pool)
             .await;
     if let Err(db_err) = resp {
@@ -279,8 +530,17 @@ async fn _delete_howto(
 
 #[derive(Debug, sqlx::FromRow, Serialize)]
 struct HowToDbRow {
+    // The row id never leaves the server.
+    #[serde(skip)]
     id: i32,
+    #[serde(rename = "id")]
+    public_id: Uuid,
     title: String,
+    visibility: visibility::Visibility,
+    /// `None` while it's a draft.
+    published_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Sent back with edits, see `versions`.
+    version: i32,
 }
 
 fn validate_length(max: usize, min: usize, input: &str) -> Result<(), String> {
@@ -294,50 +554,84 @@ fn validate_length(max: usize, min: usize, input: &str) -> Result<(), String> {
         _ => Ok(()),
     }
 }
-#[derive(Deserialize, sqlx::FromRow, Serialize)]
+
+/// How long a step takes, which the profile adds up.
+fn validate_seconds(seconds: i32) -> Result<(), ServerError> {
+    if seconds < 0 {
+        return Err(ServerError::ValidationError {
+            field: "seconds",
+            message: "Can't be negative".into(),
+        });
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
 struct UpdatedHowTo {
-    id: i32,
+    id: PublicId,
     title: String,
+    /// Or `If-Match`.
+    version: Option<i32>,
 }
 
 async fn update_howto(
+    req: HttpRequest,
     json: web::Json<UpdatedHowTo>,
     db_pool: web::Data<PgPool>,
-) -> impl Responder {
+    user: Option<auth::CurrentUser>,
+) -> Result<HttpResponse, ServerError> {
     let trimmed_title = json.title.trim();
 
     if let Err(_msg) = validate_length(80, 1, trimmed_title) {
         // return HttpResponse::UnprocessableEntity().json(error_info);
-        return HttpResponse::Ok().body("error");
+        return Ok(HttpResponse::Ok().body("error"));
     }
 
+    let expected = versions::expected(&req, json.version)?;
+    let id = public_id::resolve(&db_pool, json.id).await?;
+    visibility::check_editor(&db_pool, id, user.as_ref()).await?;
+    let mut tx = db_pool.begin().await?;
+    versions::check(&mut tx, &db_pool, id, expected).await?;
     // What goes in is what should come out...
-    let updated: UpdatedHowTo = sqlx::query_as(
+    let mut updated: HowToDbRow = sqlx::query_as(
         r#"
 UPDATE howto
 SET title = $2
 WHERE id = $1
-RETURNING id, title
+RETURNING id, public_id, title, visibility, published_at, version
         "#,
     )
-    .bind(json.id)
-    .bind(trimmed_title.clone())
-    .fetch_one(&**db_pool)
-    .await
-    // could be more granular here...
-    .expect("Failed to update howto");
+    .bind(id)
+    .bind(trimmed_title)
+    .fetch_one(&mut tx)
+    .await?;
+    updated.version = revisions::record(
+        &mut tx,
+        updated.id,
+        user.map(|user| user.0),
+        revisions::Change::Title,
+        None,
+    )
+    .await?;
+    tx.commit().await?;
 
-    HttpResponse::Ok().json(updated)
+    Ok(HttpResponse::Ok()
+        .header(http::header::ETAG, versions::etag(updated.version))
+        .json(updated))
 }
 
 #[derive(Deserialize)]
 struct CreateHowToInput {
     title: String,
+    /// Private for logged in authors, public otherwise.
+    visibility: Option<visibility::Visibility>,
 }
 async fn create_howto(
     json: web::Json<CreateHowToInput>,
     db_pool: web::Data<PgPool>,
-) -> impl Responder {
+    // Anonymous how-tos are still allowed, they just don't show up in feeds.
+    user: Option<auth::CurrentUser>,
+) -> Result<HttpResponse, ServerError> {
     let trimmed_title = json.title.trim();
 
     if let Err(_msg) = validate_length(80, 1, trimmed_title) {
@@ -346,22 +640,41 @@ async fn create_howto(
         //     msg,
         // };
         // return HttpResponse::UnprocessableEntity().json(error_info);
-        return HttpResponse::Ok().body("error");
+        return Ok(HttpResponse::Ok().body("error"));
     }
 
-    let created_howto: HowToDbRow = sqlx::query_as(
+    let author_id = user.map(|user| user.0);
+    let visibility = json.visibility.unwrap_or(match author_id {
+        Some(_) => visibility::Visibility::Private,
+        None => visibility::Visibility::Public,
+    });
+    visibility::validate(visibility, author_id)?;
+
+    let mut tx = db_pool.begin().await?;
+    let mut created_howto: HowToDbRow = sqlx::query_as(
         r#"
-INSERT INTO howto (title)
-VALUES ($1)
-RETURNING id, title
+INSERT INTO howto (title, author_id, visibility, published_at)
+-- Nobody could publish an anonymous draft, so those are published at once.
+VALUES ($1, $2, $3, CASE WHEN $2 IS NULL THEN now() END)
+RETURNING id, public_id, title, visibility, published_at, version
     "#,
     )
     .bind(trimmed_title)
-    .fetch_one(&**db_pool)
-    .await
-    .expect("Failed to insert the row: {}");
+    .bind(author_id)
+    .bind(visibility)
+    .fetch_one(&mut tx)
+    .await?;
+    created_howto.version = revisions::record(
+        &mut tx,
+        created_howto.id,
+        author_id,
+        revisions::Change::Create,
+        None,
+    )
+    .await?;
+    tx.commit().await?;
 
-    HttpResponse::Ok().json(created_howto)
+    Ok(HttpResponse::Ok().json(created_howto))
 }
 
 #[derive(Serialize, sqlx::FromRow)]
@@ -369,19 +682,20 @@ RETURNING id, title
 struct StepDbRow {
     id: i32,
     title: String,
-    image_filename: String,
+    seconds: i32,
 }
 
 // in
 #[derive(Deserialize)]
 struct StepCreateData {
-    howto_id: i32,
+    howto_id: PublicId,
     title: String,
     seconds: i32,
 }
 async fn create_step(
     json: web::Json<StepCreateData>,
     db_pool: web::Data<PgPool>,
+    user: Option<auth::CurrentUser>,
 ) -> Result<HttpResponse, ServerError> {
     // result could be one of these two things, then a bunch of other things within that.
     // trim title input
@@ -397,6 +711,10 @@ async fn create_step(
         // return HttpResponse::UnprocessableEntity().json(error_info);
         return Ok(HttpResponse::Ok().body("todo"));
     }
+    validate_seconds(json.seconds)?;
+
+    let how_to_id = public_id::resolve(&db_pool, json.howto_id).await?;
+    visibility::check_editor(&db_pool, how_to_id, user.as_ref()).await?;
 
     // create a step, then a howto-step. In the same transaction
     // so create a transaction
@@ -404,9 +722,9 @@ async fn create_step(
     let mut tx = db_pool.begin().await?;
 
     let q = r#"
-INSERT INTO step (title)
+INSERT INTO step (title, seconds)
 VALUES ($1, $2)
-RETURNING id, title
+RETURNING id, title, seconds
 "#;
 
     let step: StepDbRow = sqlx::query_as(q)
@@ -416,32 +734,78 @@ RETURNING id, title
         .await?;
 
     let q2 = r#"
-INSERT INTO howto_step (step_id, howto_id)
-VALUES ($1, $2)
+INSERT INTO howto_step (step_id, howto_id, position)
+VALUES ($1, $2, 0)
 "#;
     sqlx::query(q2)
         .bind(step.id)
-        .bind(json.howto_id)
+        .bind(how_to_id)
         .execute(&mut tx)
-        .await
-        .expect("failed to insert howto_step");
+        .await?;
+    revisions::record(
+        &mut tx,
+        how_to_id,
+        user.map(|user| user.0),
+        revisions::Change::StepAdded,
+        None,
+    )
+    .await?;
 
     tx.commit().await?;
 
     // return HttpResponse::Ok().json(step);
-    return Ok(HttpResponse::Ok().body("ok"));
+    Ok(HttpResponse::Ok().body("ok"))
 }
 
-#[derive(Deserialize, sqlx::FromRow, Serialize)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
 struct StepDeleteData {
     id: i32,
-    image_filename: String,
+    media_filenames: Vec<String>,
+    /// The how-to's version after the delete.
+    version: i32,
+}
+
+/// The how-to a step is in, which is the one whose version edits to the
+/// step are checked against.
+async fn step_how_to(
+    db_pool: &PgPool,
+    step_id: i32,
+) -> Result<i32, ServerError> {
+    let (how_to_id,): (i32,) = sqlx::query_as(
+        "SELECT howto_id FROM howto_step WHERE step_id = $1 ORDER BY id LIMIT 1",
+    )
+    .bind(step_id)
+    .fetch_optional(db_pool)
+    .await?
+    .ok_or(ServerError::NotFound)?;
+    Ok(how_to_id)
 }
 
 async fn delete_step(
+    req: HttpRequest,
     web::Path(id): web::Path<i32>,
     db_pool: web::Data<PgPool>,
-) -> impl Responder {
+    settings: web::Data<Settings>,
+    user: Option<auth::CurrentUser>,
+) -> Result<HttpResponse, ServerError> {
+    let expected = versions::expected(&req, None)?;
+    let how_to_id = step_how_to(&db_pool, id).await?;
+    visibility::check_editor(&db_pool, how_to_id, user.as_ref()).await?;
+    let mut tx = db_pool.begin().await?;
+    versions::check(&mut tx, &db_pool, how_to_id, expected).await?;
+
+    let media_filenames: Vec<(String,)> = sqlx::query_as(
+        r#"
+DELETE FROM step_media
+WHERE step_id = $1
+RETURNING filename
+"#,
+    )
+    .bind(id)
+    .fetch_all(&mut tx)
+    .await?;
+
     // NOTE: this will delete ALL references that have to do with this step.. not what's wanted in the future, but good for now
     sqlx::query(
         r#"
@@ -450,60 +814,105 @@ WHERE step_id = $1
 "#,
     )
     .bind(id)
-    .execute(&**db_pool)
-    .await
-    .expect("failed to delete howto step");
+    .execute(&mut tx)
+    .await?;
 
-    let deleted_step: StepDeleteData = sqlx::query_as(
+    let (id,): (i32,) = sqlx::query_as(
         r#"
 DELETE FROM step
 WHERE id = $1
-RETURNING id, image_filename
+RETURNING id
 "#,
     )
     .bind(id)
-    .fetch_one(&**db_pool)
-    .await
-    .expect("failed to delete step");
+    .fetch_one(&mut tx)
+    .await?;
 
-    // TODO: delete from file system, async
+    let version = revisions::record(
+        &mut tx,
+        how_to_id,
+        user.map(|user| user.0),
+        revisions::Change::StepRemoved,
+        None,
+    )
+    .await?;
+    let media_filenames: Vec<String> =
+        media_filenames.into_iter().map(|(f,)| f).collect();
+    let unreferenced =
+        revisions::unreferenced(&mut tx, media_filenames.clone()).await?;
 
-    use std::fs;
-    let s = deleted_step.image_filename.clone();
-    let e = web::block(move || {
-        fs::remove_file(format!("./tmp/{}", deleted_step.image_filename))
-    })
-    .await;
+    tx.commit().await?;
 
-    e.expect("failed to delete the image");
+    // Only remove files once the rows are gone, so a failed delete never
+    // leaves a step pointing at missing media.
+    media::remove_files(&settings.storage_root, unreferenced).await?;
 
-    HttpResponse::Ok().json(s)
+    Ok(HttpResponse::Ok().json(StepDeleteData {
+        id,
+        media_filenames,
+        version,
+    }))
 }
 
 #[derive(Deserialize)]
 struct StepUpdateData {
     id: i32,
     title: String,
+    /// Unchanged if left out.
+    seconds: Option<i32>,
+    /// Of the how-to the step is in. Or `If-Match`.
+    version: Option<i32>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StepUpdateResponse {
+    #[serde(flatten)]
+    step: StepDbRow,
+    version: i32,
 }
 
 async fn update_step(
+    req: HttpRequest,
     json: web::Json<StepUpdateData>,
     db_pool: web::Data<PgPool>,
-) -> impl Responder {
+    user: Option<auth::CurrentUser>,
+) -> Result<HttpResponse, ServerError> {
+    let expected = versions::expected(&req, json.version)?;
+    if let Some(seconds) = json.seconds {
+        validate_seconds(seconds)?;
+    }
+    let how_to_id = step_how_to(&db_pool, json.id).await?;
+    visibility::check_editor(&db_pool, how_to_id, user.as_ref()).await?;
+    let mut tx = db_pool.begin().await?;
+    versions::check(&mut tx, &db_pool, how_to_id, expected).await?;
     let updated_step: StepDbRow = sqlx::query_as(
         r#"
             UPDATE step
-            SET title = $1
+            SET title = $1, seconds = COALESCE($3, seconds)
             WHERE id = $2 
             RETURNING id, title, seconds
         "#,
     )
     .bind(&json.title)
     .bind(json.id)
-    .fetch_one(&**db_pool)
-    .await
-    .expect("failed to update step");
-    HttpResponse::Ok().json(updated_step)
+    .bind(json.seconds)
+    .fetch_one(&mut tx)
+    .await?;
+    let version = revisions::record(
+        &mut tx,
+        how_to_id,
+        user.map(|user| user.0),
+        revisions::Change::StepUpdated,
+        None,
+    )
+    .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(StepUpdateResponse {
+        step: updated_step,
+        version,
+    }))
 }
 
 // In this case, maybe get the path ID?
@@ -521,131 +930,189 @@ async fn update_step(
 // "todo"
 // }
 
+// Plenty for an id or a title, small enough that junk can't pile up in memory.
+const TEXT_FIELD_BYTES: usize = 1024;
+// Each file is capped by size, this caps how many are held at once.
+const MAX_STEP_MEDIA: usize = 10;
+
 // This is actually 'new step'
 struct StepInput {
     title: String,
-    how_to_id: i32,
-    image: Image,
-}
-
-struct Image {
-    filename: String,
+    seconds: i32,
+    how_to_id: PublicId,
+    media: Vec<media::MediaUpload>,
 }
 
 // Need to know that all these things really exist before starting to save to FS or DB.
 
 pub async fn img_upload(
     db_pool: web::Data<PgPool>,
+    settings: web::Data<Settings>,
+    user: Option<auth::CurrentUser>,
     mut payload: Multipart,
 ) -> Result<HttpResponse, ServerError> {
     // Should accept `IMAGE_STAR, "image/*"` from mime-types? Is that what a "route guard" is?
 
-    let mut how_to_id: Option<i32> = None;
-    let mut image: Option<Image> = None;
+    let mut how_to_id: Option<PublicId> = None;
     let mut title: Option<String> = None;
+    let mut seconds = 0;
+    // Media is kept in the order it was sent, which becomes its position.
+    let mut uploads: Vec<media::MediaUpload> = Vec::new();
 
-    // Process input. All inputs must exist.
-    // So this is like a loop that reads from events coming in from memory, but maybe? Only if they are there?
-    // What if they never show up? How long does it wait?
+    // Process input. `howToId` and `title` must exist, `seconds` defaults to
+    // 0, and `image` and `video` may be sent up to `MAX_STEP_MEDIA` times
+    // between them (including zero, for a text-only step).
     while let Ok(Some(mut field)) = payload.try_next().await {
-        let content_type = field
-            .content_disposition()
-            .expect("Failed to read content disposition");
-
-        let field_name = content_type.get_name().unwrap();
+        let field_name = match field.content_disposition() {
+            Some(cd) => cd.get_name().unwrap_or_default().to_string(),
+            None => continue,
+        };
 
-        match field_name {
+        match field_name.as_str() {
             "howToId" => {
-                while let Some(chunk) = field.next().await {
-                    let value = chunk.expect("failed to read chunk");
-                    // Route validation is done on server side.
-                    how_to_id = Some(
-                        String::from_utf8_lossy(&value)
-                            .parse::<i32>()
-                            .expect("Failed to parse howto id"),
-                    );
-                }
+                let value =
+                    media::read_field(&mut field, "howToId", TEXT_FIELD_BYTES)
+                        .await?;
+                // Route validation is done on server side.
+                let parsed = String::from_utf8_lossy(&value).parse();
+                how_to_id =
+                    Some(parsed.map_err(|_| ServerError::ValidationError {
+                        field: "howToId",
+                        message: "Invalid how-to id".into(),
+                    })?);
             }
             "title" => {
-                while let Some(chunk) = field.next().await {
-                    let input = chunk.expect("failed to read chunk");
-                    let input_string =
-                        String::from_utf8_lossy(&input).trim().to_string();
-
-                    // The error should be a value
-                    let err = validate_length(80, 1, &input_string);
-                    if let Err(err) = err {
-                        return Err(ServerError::ValidationError {
-                            field: "title",
-                            message: err,
-                        });
-                    }
-                    title = Some(input_string);
+                let input =
+                    media::read_field(&mut field, "title", TEXT_FIELD_BYTES)
+                        .await?;
+                let input_string =
+                    String::from_utf8_lossy(&input).trim().to_string();
+
+                // The error should be a value
+                let err = validate_length(80, 1, &input_string);
+                if let Err(err) = err {
+                    return Err(ServerError::ValidationError {
+                        field: "title",
+                        message: err,
+                    });
                 }
-                // TODO: check min/max length
+                title = Some(input_string);
             }
-            "image" => {
-                // Security note: We're not storing the filename, so it does not need sanitizing.
-
-                // Basically a buffer, right? A buffer is a temp area in memory?
-                let mut image_bytes = Vec::new();
-                while let Some(chunk) = field.next().await {
-                    let data = chunk.unwrap(); // "failed to read input - network error" - but what is this error actually?
-                    image_bytes.extend_from_slice(&data[..]);
-                }
-                use uuid::Uuid;
-                let img_uuid = Uuid::new_v4().to_simple();
-                web::block::<_, _, String>(move || {
-                    use std::fs::File;
-                    let mut f = File::create(format!(
-                        "./tmp/{}.jpg",
-                        &img_uuid.to_string()
-                    ))
-                    .unwrap();
-                    f.write_all(&image_bytes).unwrap();
-                    Ok(())
-                }).await;
-
-                image = Some(Image {
-                    filename: format!("{}.jpg", img_uuid.to_string()),
+            "seconds" => {
+                let value =
+                    media::read_field(&mut field, "seconds", TEXT_FIELD_BYTES)
+                        .await?;
+                seconds = String::from_utf8_lossy(&value)
+                    .trim()
+                    .parse()
+                    .map_err(|_| ServerError::ValidationError {
+                        field: "seconds",
+                        message: "Expected a whole number".into(),
+                    })?;
+                validate_seconds(seconds)?;
+            }
+            "image" | "video" if uploads.len() == MAX_STEP_MEDIA => {
+                return Err(ServerError::ValidationError {
+                    field: "media",
+                    message: format!(
+                        "A step can have at most {} images and videos",
+                        MAX_STEP_MEDIA
+                    ),
                 });
             }
+            "image" => {
+                uploads.push(media::read_image(&mut field, &settings).await?)
+            }
+            "video" => {
+                uploads.push(media::read_video(&mut field, &settings).await?)
+            }
             _ => {
-                ()
                 // return Ok(HttpResponse::UnprocessableEntity()
                 //     .body(format!("Field '{}' does not exist.", field_name)));
             }
         }
     }
 
-    // Why is `image != None` not possible?
     let step_input = StepInput {
         // TODO: position field is needed as well
-        image: image.expect("image not present"),
-        how_to_id: how_to_id.expect("id not present"),
-        title: title.expect("title not present"),
+        how_to_id: how_to_id.ok_or(ServerError::ValidationError {
+            field: "howToId",
+            message: "How-to id not present".into(),
+        })?,
+        title: title.ok_or(ServerError::ValidationError {
+            field: "title",
+            message: "Title not present".into(),
+        })?,
+        seconds,
+        media: uploads,
     };
 
     // Sweet. Input is now validated, and in memory. Time to persist it.
-
-    // Now for the operations:
+    let StepInput {
+        title,
+        seconds,
+        how_to_id: how_to_public_id,
+        media: uploads,
+    } = step_input;
+    // Someone who can't see the how-to can't tell it from a made up id.
+    let how_to_id = async {
+        let id = public_id::resolve(&db_pool, how_to_public_id).await?;
+        visibility::check_editor(&db_pool, id, user.as_ref()).await?;
+        Ok(id)
+    }
+    .await
+    .map_err(|e| match e {
+        ServerError::NotFound => ServerError::ValidationError {
+            field: "howToId",
+            message: "Invalid how-to id".into(),
+        },
+        e => e,
+    })?;
+
+    // Files go first, so the rows never point at media that isn't there.
+    // Until the transaction commits, the guard removes them again if this
+    // returns early or is dropped mid-way (e.g. during shutdown).
+    let mut written = media::PendingFiles::new(&settings.storage_root);
+    let mut media_types = Vec::with_capacity(uploads.len());
+    for upload in uploads {
+        written.write(upload.bytes, upload.extension).await?;
+        media_types.push(upload.media_type);
+    }
 
     // BEGIN transaction
     let mut tx = db_pool.begin().await?;
 
-    // Create step row w/file name & title
+    // Create step row w/title
     let new_step: StepRow = sqlx::query_as(
         r#"
-INSERT INTO step (title, image_filename)
+INSERT INTO step (title, seconds)
 VALUES ($1, $2)
-RETURNING *
+RETURNING id, title, seconds
         "#,
     )
-    .bind(&step_input.title)
-    .bind(&step_input.image.filename)
+    .bind(&title)
+    .bind(seconds)
     .fetch_one(&mut tx)
     .await?;
 
+    // One media row per upload, in upload order
+    for (position, (filename, media_type)) in
+        written.filenames().iter().zip(&media_types).enumerate()
+    {
+        sqlx::query(
+            r#"
+INSERT INTO step_media (step_id, position, filename, media_type)
+VALUES ($1, $2, $3, $4)
+            "#,
+        )
+        .bind(new_step.id)
+        .bind(position as i32)
+        .bind(filename)
+        .bind(media_type)
+        .execute(&mut tx)
+        .await?;
+    }
+
     const POSITION: i32 = 0;
 
     // Create howto_step with step_id and howto_id
@@ -656,40 +1123,47 @@ VALUES ($1, $2, $3)
 RETURNING *
         "#,
     )
-    .bind(step_input.how_to_id)
+    .bind(how_to_id)
     .bind(new_step.id)
     .bind(POSITION)
     .fetch_one(&mut tx)
     .await?;
 
-    // How can this be based on an environment variable? Docker during dev, NFS during prod.
-    // let filepath = format!("./tmp/{}", &step_input.image.filename);
-
-    // // create a file handle
-    // let mut f = web::block(|| std::fs::File::create(filepath))
-    //     .await
-    //     .expect("could not create file");
-
-    // // write to that file handle in a thread pool.
-    // web::block(move || f.write(&step_input.image.image_bytes).map(|_| f))
-    //     .await
-    //     .expect("file system write error");
-    // Is transaction automatically aborted if the function throws an error? Are all values in the function then 'dropped'?
-
-    let err = ServerError::DatabaseError("err".into());
-    log::debug!("{}", err);
+    let version = revisions::record(
+        &mut tx,
+        how_to_id,
+        user.map(|user| user.0),
+        revisions::Change::StepAdded,
+        None,
+    )
+    .await?;
 
     // COMMIT transaction
     tx.commit().await?;
+    let filenames = written.keep();
+
+    let step_id = new_step.id;
     let r = CreateStepResponse {
         position: new_howto_step.position,
-        howto_id: new_howto_step.howto_id,
-        step_id: new_step.id,
+        howto_id: how_to_public_id.0,
+        version,
+        step_id,
         title: new_step.title,
-        image_filename: new_step.image_filename,
+        seconds: new_step.seconds,
+        media: filenames
+            .into_iter()
+            .zip(media_types)
+            .enumerate()
+            .map(|(position, (filename, media_type))| media::StepMediaRow {
+                step_id,
+                position: position as i32,
+                filename,
+                media_type,
+            })
+            .collect(),
     };
 
-    // Return the position, stepid, howtoid, image file name, title.
+    // Return the position, stepid, howtoid, media file names, title.
     Ok(HttpResponse::Ok().json(r))
 }
 
@@ -697,16 +1171,19 @@ RETURNING *
 #[serde(rename_all = "camelCase")]
 struct CreateStepResponse {
     position: i32,
-    howto_id: i32,
+    howto_id: Uuid,
+    /// The how-to's version with the step added.
+    version: i32,
     step_id: i32,
     title: String,
-    image_filename: String,
+    seconds: i32,
+    media: Vec<media::StepMediaRow>,
 }
 
 #[derive(sqlx::FromRow)]
 struct HowToStepRow {
     position: i32,
-    howto_id: i32,
+    // howto_id: i32,
     // step_id: i32,
 }
 
@@ -714,5 +1191,5 @@ struct HowToStepRow {
 struct StepRow {
     id: i32,
     title: String,
-    image_filename: String,
+    seconds: i32,
 }
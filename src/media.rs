@@ -0,0 +1,445 @@
+use crate::{
+    auth::CurrentUser, avatars, metrics, settings::Settings, ServerError,
+};
+use actix_files::NamedFile;
+use actix_web::{
+    error::BlockingError, http::header, web, HttpRequest, HttpResponse,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename = "media_type", rename_all = "lowercase")]
+pub enum MediaType {
+    Image,
+    Video,
+}
+
+impl MediaType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaType::Image => "image",
+            MediaType::Video => "video",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct StepMediaRow {
+    #[serde(skip)]
+    pub step_id: i32,
+    pub position: i32,
+    pub filename: String,
+    pub media_type: MediaType,
+}
+
+/// One of the parsers in `video`.
+type DurationReader = fn(&[u8]) -> Option<Duration>;
+
+/// An uploaded file that has been checked, but not yet written to disk.
+pub struct MediaUpload {
+    pub media_type: MediaType,
+    pub extension: &'static str,
+    pub bytes: Vec<u8>,
+}
+
+/// Reads an `image` field. Images are resized to jpeg by the client.
+pub async fn read_image(
+    field: &mut actix_multipart::Field,
+    settings: &Settings,
+) -> Result<MediaUpload, ServerError> {
+    let bytes = read_field(field, "image", settings.max_image_bytes).await?;
+    metrics::UPLOAD_BYTES
+        .with_label_values(&[MediaType::Image.as_str()])
+        .inc_by(bytes.len() as u64);
+    Ok(MediaUpload {
+        media_type: MediaType::Image,
+        extension: "jpg",
+        bytes,
+    })
+}
+
+/// Reads a `video` field, which must be a short MP4 or WebM clip.
+pub async fn read_video(
+    field: &mut actix_multipart::Field,
+    settings: &Settings,
+) -> Result<MediaUpload, ServerError> {
+    let invalid = |message: String| ServerError::ValidationError {
+        field: "video",
+        message,
+    };
+
+    let mime = field.content_type();
+    let (extension, read_duration): (_, DurationReader) =
+        match (mime.type_().as_str(), mime.subtype().as_str()) {
+            ("video", "mp4") => ("mp4", crate::video::mp4_duration),
+            ("video", "webm") => ("webm", crate::video::webm_duration),
+            _ => {
+                return Err(invalid(format!(
+                    "Unsupported video type {}. Use MP4 or WebM",
+                    mime
+                )))
+            }
+        };
+
+    let bytes = read_field(field, "video", settings.max_video_bytes).await?;
+    let timer = metrics::MEDIA_PROCESSING_DURATION
+        .with_label_values(&[MediaType::Video.as_str()])
+        .start_timer();
+    let duration = read_duration(&bytes);
+    timer.observe_duration();
+    let duration = duration
+        .ok_or_else(|| invalid("Could not read video duration".into()))?;
+    if duration > settings.max_video_duration() {
+        return Err(invalid(format!(
+            "Video too long. Max {} seconds",
+            settings.max_video_seconds
+        )));
+    }
+    metrics::UPLOAD_BYTES
+        .with_label_values(&[MediaType::Video.as_str()])
+        .inc_by(bytes.len() as u64);
+
+    Ok(MediaUpload {
+        media_type: MediaType::Video,
+        extension,
+        bytes,
+    })
+}
+
+/// Files are written under this suffix and renamed once complete, so a file
+/// with its real name is never half-written.
+const PARTIAL_SUFFIX: &str = ".part";
+
+/// Removes files left half-written by a process that was killed mid-upload.
+/// Other instances may share the directory, so only files untouched for
+/// `older_than` are taken as abandoned rather than still being written.
+pub fn remove_partial_files(
+    storage_root: &Path,
+    older_than: Duration,
+) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(storage_root)? {
+        let entry = entry?;
+        let partial = entry
+            .file_name()
+            .to_string_lossy()
+            .ends_with(PARTIAL_SUFFIX);
+        if !partial {
+            continue;
+        }
+        let age = entry.metadata()?.modified()?.elapsed().unwrap_or_default();
+        if age < older_than {
+            continue;
+        }
+        match fs::remove_file(entry.path()) {
+            // Another instance starting up got there first.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+            Ok(()) => removed += 1,
+        }
+    }
+    Ok(removed)
+}
+
+/// Files written for a request that hasn't been committed yet. Dropping this
+/// removes them, unless `keep` was called.
+pub struct PendingFiles {
+    storage_root: PathBuf,
+    filenames: Vec<String>,
+    /// Set once the guard is dropped. A write that is still running on the
+    /// blocking pool then throws its file away instead of renaming it into
+    /// place after the guard has already cleaned up.
+    dropped: Arc<Mutex<bool>>,
+}
+
+impl PendingFiles {
+    pub fn new(storage_root: &Path) -> Self {
+        PendingFiles {
+            storage_root: storage_root.to_path_buf(),
+            filenames: Vec::new(),
+            dropped: Arc::new(Mutex::new(false)),
+        }
+    }
+
+    /// Writes an uploaded file under a fresh random name and returns that
+    /// name. The client's filename is never used, so it doesn't need
+    /// sanitizing.
+    pub async fn write(
+        &mut self,
+        bytes: Vec<u8>,
+        extension: &'static str,
+    ) -> Result<String, ServerError> {
+        let filename = format!("{}.{}", Uuid::new_v4().to_simple(), extension);
+        self.write_named(filename.clone(), bytes).await?;
+        Ok(filename)
+    }
+
+    /// Like `write`, for callers that pick the name themselves.
+    pub async fn write_named(
+        &mut self,
+        filename: String,
+        bytes: Vec<u8>,
+    ) -> Result<(), ServerError> {
+        let path = self.storage_root.join(&filename);
+        let partial_path = self
+            .storage_root
+            .join(format!("{}{}", filename, PARTIAL_SUFFIX));
+        // Owned by the guard before the write starts, in case this future is
+        // dropped while the blocking write carries on.
+        self.filenames.push(filename);
+        let dropped = Arc::clone(&self.dropped);
+        web::block(move || {
+            let mut f = fs::File::create(&partial_path)?;
+            let written = f.write_all(&bytes).and_then(|_| f.sync_all());
+            // Held across the rename, so the guard can't clean up in between.
+            let dropped = dropped.lock().unwrap_or_else(|e| e.into_inner());
+            let renamed = written.and_then(|_| {
+                if *dropped {
+                    Err(io::Error::new(
+                        io::ErrorKind::Interrupted,
+                        "upload was abandoned",
+                    ))
+                } else {
+                    fs::rename(&partial_path, path)
+                }
+            });
+            if let Err(e) = renamed {
+                let _ = fs::remove_file(&partial_path);
+                return Err(e);
+            }
+            Ok(())
+        })
+        .await
+        .map_err(|e| ServerError::FileSystemError(e.to_string()))
+    }
+
+    pub fn filenames(&self) -> &[String] {
+        &self.filenames
+    }
+
+    /// The files are referenced now, so leave them be.
+    pub fn keep(mut self) -> Vec<String> {
+        std::mem::take(&mut self.filenames)
+    }
+}
+
+impl Drop for PendingFiles {
+    fn drop(&mut self) {
+        let mut dropped =
+            self.dropped.lock().unwrap_or_else(|e| e.into_inner());
+        *dropped = true;
+        // Blocking, but it's a handful of unlinks and this can't be async.
+        for filename in &self.filenames {
+            match fs::remove_file(self.storage_root.join(filename)) {
+                // A write that failed, or hasn't got as far as renaming.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => log::error!("failed to remove {}: {}", filename, e),
+                Ok(()) => (),
+            }
+        }
+    }
+}
+
+/// Removes files from the media directory. Files that are already gone are
+/// not an error.
+pub async fn remove_files(
+    storage_root: &Path,
+    filenames: Vec<String>,
+) -> Result<(), ServerError> {
+    let storage_root = storage_root.to_path_buf();
+    web::block(move || {
+        for filename in filenames {
+            match fs::remove_file(storage_root.join(filename)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(e)
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    })
+    .await
+    .map_err(|e| ServerError::FileSystemError(e.to_string()))
+}
+
+/// Reads a whole multipart field into memory, giving up once it grows past
+/// `limit` bytes.
+pub async fn read_field(
+    field: &mut actix_multipart::Field,
+    name: &'static str,
+    limit: usize,
+) -> Result<Vec<u8>, ServerError> {
+    use futures::StreamExt;
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = field.next().await {
+        let data = chunk.map_err(|e| ServerError::ValidationError {
+            field: name,
+            message: e.to_string(),
+        })?;
+        if bytes.len() + data.len() > limit {
+            return Err(ServerError::ValidationError {
+                field: name,
+                message: format!("Too large. Max {} bytes", limit),
+            });
+        }
+        bytes.extend_from_slice(&data[..]);
+    }
+    Ok(bytes)
+}
+
+/// Step media is always named `<uuid>.<ext>`, which also rules out path
+/// traversal. Avatars have their own names, see `avatars::stem_of`.
+pub fn is_media_name(filename: &str) -> bool {
+    match filename.split_once('.') {
+        Some((stem, ext)) => {
+            !stem.is_empty()
+                && stem.bytes().all(|b| b.is_ascii_hexdigit())
+                && ext.bytes().all(|b| b.is_ascii_alphanumeric())
+        }
+        None => false,
+    }
+}
+
+/// Serves a file from the media directory, but only if a step that belongs to
+/// a how-to the caller can read, or a user's avatar, still uses it. Anything
+/// else is a 404, whether or not it's on disk.
+pub async fn serve(
+    req: HttpRequest,
+    db_pool: web::Data<PgPool>,
+    settings: web::Data<Settings>,
+    web::Path(filename): web::Path<String>,
+    user: Option<CurrentUser>,
+) -> Result<HttpResponse, ServerError> {
+    let referenced = if let Some(stem) = avatars::stem_of(&filename) {
+        avatars::in_use(&db_pool, stem).await?
+    } else if is_media_name(&filename) {
+        let (referenced,): (bool,) = sqlx::query_as(
+            r#"
+SELECT EXISTS (
+    SELECT 1
+    FROM step_media, howto_step, howto
+    WHERE step_media.filename = $1
+    AND howto_step.step_id = step_media.step_id
+    AND howto.id = howto_step.howto_id
+    AND (
+        howto.visibility <> 'private' AND howto.published_at IS NOT NULL
+        OR howto.author_id = $2
+    )
+)
+"#,
+        )
+        .bind(&filename)
+        .bind(user.map(|user| user.0))
+        .fetch_one(&**db_pool)
+        .await?;
+        referenced
+    } else {
+        false
+    };
+    if !referenced {
+        return Err(ServerError::NotFound);
+    }
+
+    let path = settings.storage_root.join(&filename);
+    let file = web::block(move || -> io::Result<_> {
+        // Go by what's in the file rather than the extension it was given.
+        let mut head = [0; 16];
+        let len = fs::File::open(&path)?.read(&mut head)?;
+        let file = NamedFile::open(&path)?;
+        Ok(file.set_content_type(sniff_content_type(&head[..len])))
+    })
+    .await
+    .map_err(|e| match e {
+        BlockingError::Error(e) if e.kind() == io::ErrorKind::NotFound => {
+            ServerError::NotFound
+        }
+        e => ServerError::FileSystemError(e.to_string()),
+    })?;
+
+    // NamedFile takes care of ETag/Last-Modified and `Range` requests, which
+    // mobile browsers need in order to seek through video clips.
+    let mut response = file
+        .into_response(&req)
+        .map_err(|e| ServerError::FileSystemError(e.to_string()))?;
+    response.headers_mut().insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        header::HeaderValue::from_static("nosniff"),
+    );
+    Ok(response)
+}
+
+fn sniff_content_type(head: &[u8]) -> mime::Mime {
+    match head {
+        [0xFF, 0xD8, 0xFF, ..] => mime::IMAGE_JPEG,
+        [0x89, b'P', b'N', b'G', ..] => mime::IMAGE_PNG,
+        [b'G', b'I', b'F', b'8', ..] => mime::IMAGE_GIF,
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => {
+            "image/webp".parse().unwrap()
+        }
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => {
+            "video/mp4".parse().unwrap()
+        }
+        [0x1A, 0x45, 0xDF, 0xA3, ..] => "video/webm".parse().unwrap(),
+        _ => mime::APPLICATION_OCTET_STREAM,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_by_content() {
+        let cases: &[(&[u8], &str)] = &[
+            (&[0xFF, 0xD8, 0xFF, 0xE0], "image/jpeg"),
+            (b"\x89PNG\r\n\x1a\n", "image/png"),
+            (b"GIF89a", "image/gif"),
+            (b"RIFF\0\0\0\0WEBPVP8 ", "image/webp"),
+            (b"\0\0\0\x18ftypisom", "video/mp4"),
+            (&[0x1A, 0x45, 0xDF, 0xA3, 0x01], "video/webm"),
+        ];
+        for (head, expected) in cases {
+            assert_eq!(sniff_content_type(head).as_ref(), *expected);
+        }
+    }
+
+    #[test]
+    fn unknown_or_short_is_octet_stream() {
+        for head in [&b""[..], b"<html>", b"RIFF\0\0\0\0WAVE", &[0xFF, 0xD8]] {
+            assert_eq!(
+                sniff_content_type(head),
+                mime::APPLICATION_OCTET_STREAM
+            );
+        }
+    }
+
+    #[test]
+    fn media_names() {
+        assert!(is_media_name("0123abcdef.jpg"));
+        assert!(is_media_name("67e5504410b1426f9247bb680e5fe0c8.webm"));
+        for name in [
+            "",
+            ".jpg",
+            "abc",
+            "../secret.jpg",
+            "abc.j/pg",
+            "abc.tar.gz",
+            "xyz.jpg",
+            "abc-64.jpg",
+        ] {
+            assert!(!is_media_name(name), "{}", name);
+        }
+    }
+}
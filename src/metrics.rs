@@ -0,0 +1,108 @@
+//! Prometheus metrics, served at `/metrics`.
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    web, Error, HttpResponse,
+};
+use futures::future::{FutureExt, LocalBoxFuture};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge,
+    Encoder, HistogramVec, IntCounterVec, IntGauge, TextEncoder,
+};
+use sqlx::postgres::PgPool;
+use std::time::Instant;
+
+lazy_static! {
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "http_requests_total",
+        "HTTP requests by route, method and status",
+        &["route", "method", "status"]
+    )
+    .unwrap();
+    static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "http_request_duration_seconds",
+        "Time to respond to a request, by route",
+        &["route"]
+    )
+    .unwrap();
+    static ref DB_POOL_CONNECTIONS: IntGauge = register_int_gauge!(
+        "db_pool_connections",
+        "Open database connections, idle or in use"
+    )
+    .unwrap();
+    static ref DB_POOL_IDLE: IntGauge = register_int_gauge!(
+        "db_pool_idle_connections",
+        "Open database connections not in use"
+    )
+    .unwrap();
+    pub static ref UPLOAD_BYTES: IntCounterVec = register_int_counter_vec!(
+        "upload_bytes_total",
+        "Bytes of media accepted from uploads, by media type",
+        &["media_type"]
+    )
+    .unwrap();
+    pub static ref MEDIA_PROCESSING_DURATION: HistogramVec =
+        register_histogram_vec!(
+            "media_processing_duration_seconds",
+            "Time to parse or resize a received upload, by media type",
+            &["media_type"]
+        )
+        .unwrap();
+    pub static ref SERVER_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "server_errors_total",
+        "Errors returned to clients, by ServerError variant",
+        &["variant"]
+    )
+    .unwrap();
+}
+
+/// Counts and times every request, labelled with the route pattern (like
+/// `/api/how-to/{id}`) so ids don't blow up the number of series.
+pub fn track_requests<S, B>(
+    req: ServiceRequest,
+    srv: &mut S,
+) -> LocalBoxFuture<'static, Result<ServiceResponse<B>, Error>>
+where
+    S: Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse<B>,
+        Error = Error,
+    >,
+    S::Future: 'static,
+    B: 'static,
+{
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let response = srv.call(req);
+    async move {
+        let response = response.await?;
+        let route = response
+            .request()
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".into());
+        HTTP_REQUESTS
+            .with_label_values(&[&route, &method, response.status().as_str()])
+            .inc();
+        HTTP_REQUEST_DURATION
+            .with_label_values(&[&route])
+            .observe(start.elapsed().as_secs_f64());
+        Ok(response)
+    }
+    .boxed_local()
+}
+
+pub async fn metrics(db_pool: web::Data<PgPool>) -> HttpResponse {
+    // Pool numbers are read when scraped rather than tracked as they change.
+    DB_POOL_CONNECTIONS.set(db_pool.size() as i64);
+    DB_POOL_IDLE.set(db_pool.num_idle() as i64);
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut body) {
+        return HttpResponse::InternalServerError().body(e.to_string());
+    }
+    HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(body)
+}
@@ -0,0 +1,169 @@
+//! Schema migrations. Going up is refinery's job, using the files in
+//! `src/sql_migrations`. Going down uses the file with the same name in
+//! `src/sql_migrations_down`, which refinery doesn't know about.
+
+use crate::{settings::Settings, ServerSetupError};
+use refinery::config::Config;
+use sqlx::{postgres::PgPool, Executor};
+use std::str::FromStr;
+
+mod embeded {
+    use refinery::embed_migrations;
+    embed_migrations!("./src/sql_migrations");
+}
+
+/// Every migration that can be undone. Add the down file here along with
+/// each new migration.
+const DOWN_MIGRATIONS: &[(i32, &str)] = &[
+    (1, include_str!("sql_migrations_down/V1__init.sql")),
+    (2, include_str!("sql_migrations_down/V2__step_media.sql")),
+    (
+        3,
+        include_str!("sql_migrations_down/V3__step_media_type.sql"),
+    ),
+    (4, include_str!("sql_migrations_down/V4__users.sql")),
+    (5, include_str!("sql_migrations_down/V5__follow.sql")),
+    (6, include_str!("sql_migrations_down/V6__profile.sql")),
+    (
+        7,
+        include_str!("sql_migrations_down/V7__account_tokens.sql"),
+    ),
+    (8, include_str!("sql_migrations_down/V8__visibility.sql")),
+    (
+        9,
+        include_str!("sql_migrations_down/V9__howto_public_id.sql"),
+    ),
+    (10, include_str!("sql_migrations_down/V10__publish.sql")),
+    (11, include_str!("sql_migrations_down/V11__revisions.sql")),
+    (
+        12,
+        include_str!("sql_migrations_down/V12__howto_version.sql"),
+    ),
+    (13, include_str!("sql_migrations_down/V13__fork.sql")),
+];
+
+#[derive(sqlx::FromRow)]
+pub struct AppliedMigration {
+    pub version: i32,
+    pub name: String,
+    pub applied_on: String,
+}
+
+/// Applies any pending migrations and logs which ones that was.
+pub fn run(settings: &Settings) -> Result<(), ServerSetupError> {
+    let mut conn = Config::from_str(&settings.database_uri).map_err(|e| {
+        ServerSetupError::InvalidSetting {
+            name: "database_uri",
+            message: e.to_string(),
+        }
+    })?;
+    let report = embeded::migrations::runner()
+        .run(&mut conn)
+        .map_err(|e| ServerSetupError::Migration(e.to_string()))?;
+
+    for migration in report.applied_migrations() {
+        log::info!(
+            "applied migration V{}__{}",
+            migration.version(),
+            migration.name()
+        );
+    }
+    if report.applied_migrations().is_empty() {
+        log::info!(
+            "database schema is up to date at V{}",
+            latest().unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+/// (version, name) of every migration in this build.
+pub fn embedded() -> Vec<(i32, String)> {
+    embeded::migrations::runner()
+        .get_migrations()
+        .iter()
+        .map(|m| (m.version() as i32, m.name().to_string()))
+        .collect()
+}
+
+/// The newest migration this build knows about.
+pub fn latest() -> Option<i64> {
+    embedded()
+        .into_iter()
+        .map(|(version, _)| version as i64)
+        .max()
+}
+
+pub fn down_sql(version: i32) -> Option<&'static str> {
+    DOWN_MIGRATIONS
+        .iter()
+        .find(|(v, _)| *v == version)
+        .map(|(_, sql)| *sql)
+}
+
+pub async fn applied(
+    db_pool: &PgPool,
+) -> Result<Vec<AppliedMigration>, sqlx::Error> {
+    let applied = sqlx::query_as(
+        r#"
+SELECT version, name, applied_on
+FROM refinery_schema_history
+ORDER BY version
+"#,
+    )
+    .fetch_all(db_pool)
+    .await;
+    match applied {
+        // Nothing has ever been applied to this database.
+        Err(sqlx::Error::Database(e))
+            if e.code().as_deref() == Some("42P01") =>
+        {
+            Ok(Vec::new())
+        }
+        applied => applied,
+    }
+}
+
+/// Undoes every applied migration newer than `to`, newest first, in one
+/// transaction. Nothing is touched unless all of them can be undone.
+pub async fn rollback(
+    db_pool: &PgPool,
+    to: i32,
+) -> Result<Vec<AppliedMigration>, ServerSetupError> {
+    let mut to_undo: Vec<AppliedMigration> = applied(db_pool)
+        .await?
+        .into_iter()
+        .filter(|m| m.version > to)
+        .collect();
+    to_undo.reverse();
+
+    if let Some(m) = to_undo.iter().find(|m| down_sql(m.version).is_none()) {
+        return Err(ServerSetupError::Migration(format!(
+            "V{}__{} has no down migration",
+            m.version, m.name
+        )));
+    }
+
+    let mut tx = db_pool.begin().await?;
+    for migration in &to_undo {
+        // Passed as a plain string, so postgres takes several statements.
+        let sql = down_sql(migration.version).unwrap_or_default();
+        (&mut tx).execute(sql).await.map_err(|e| {
+            ServerSetupError::Migration(format!(
+                "V{}__{} down: {}",
+                migration.version, migration.name, e
+            ))
+        })?;
+        sqlx::query("DELETE FROM refinery_schema_history WHERE version = $1")
+            .bind(migration.version)
+            .execute(&mut tx)
+            .await?;
+        log::info!(
+            "rolled back migration V{}__{}",
+            migration.version,
+            migration.name
+        );
+    }
+    tx.commit().await?;
+    Ok(to_undo)
+}
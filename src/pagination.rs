@@ -0,0 +1,92 @@
+//! `?limit=&offset=` for list endpoints.
+
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 100;
+
+#[derive(Deserialize)]
+pub struct PageQuery {
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+impl PageQuery {
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+
+    /// Queries ask for one more row than the page holds, to know whether
+    /// there's a next page without counting.
+    pub fn fetch_limit(&self) -> i64 {
+        self.limit() + 1
+    }
+
+    pub fn page<T>(&self, mut items: Vec<T>) -> Page<T> {
+        let limit = self.limit();
+        let more = items.len() as i64 > limit;
+        items.truncate(limit as usize);
+        Page {
+            items,
+            next_offset: if more {
+                Some(self.offset() + limit)
+            } else {
+                None
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Where the next page starts, if there is one.
+    pub next_offset: Option<i64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(limit: Option<i64>, offset: Option<i64>) -> PageQuery {
+        PageQuery { limit, offset }
+    }
+
+    #[test]
+    fn defaults() {
+        let q = query(None, None);
+        assert_eq!((q.limit(), q.offset()), (DEFAULT_LIMIT, 0));
+        assert_eq!(q.fetch_limit(), DEFAULT_LIMIT + 1);
+    }
+
+    #[test]
+    fn limits_are_clamped() {
+        assert_eq!(query(Some(0), None).limit(), 1);
+        assert_eq!(query(Some(-5), None).limit(), 1);
+        assert_eq!(query(Some(1000), None).limit(), MAX_LIMIT);
+        assert_eq!(query(None, Some(-3)).offset(), 0);
+    }
+
+    #[test]
+    fn page_with_more_to_come() {
+        let page = query(Some(2), Some(4)).page(vec![1, 2, 3]);
+        assert_eq!(page.items, vec![1, 2]);
+        assert_eq!(page.next_offset, Some(6));
+    }
+
+    #[test]
+    fn last_page() {
+        let page = query(Some(2), Some(4)).page(vec![1, 2]);
+        assert_eq!(page.items, vec![1, 2]);
+        assert_eq!(page.next_offset, None);
+
+        let page = query(Some(2), None).page(Vec::<i32>::new());
+        assert!(page.items.is_empty());
+        assert_eq!(page.next_offset, None);
+    }
+}
@@ -0,0 +1,140 @@
+//! How-tos are addressed by a random UUID instead of their row id, so ids
+//! can't be guessed or counted. A path may put a slug of the title in front,
+//! as in `/how-to/morning-chores-<uuid>`. The slug is only there for people
+//! to read, and a stale one still works.
+
+use crate::ServerError;
+use serde::{de, Deserialize, Deserializer};
+use sqlx::postgres::PgPool;
+use std::str::FromStr;
+use uuid::Uuid;
+
+const UUID_LEN: usize = 36;
+const SLUG_CHARS: usize = 60;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PublicId(pub Uuid);
+
+impl FromStr for PublicId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{} is not a how-to id", s);
+        let start = s.len().checked_sub(UUID_LEN).ok_or_else(invalid)?;
+        let slug = s.get(..start).ok_or_else(invalid)?;
+        if !(slug.is_empty() || slug.ends_with('-')) {
+            return Err(invalid());
+        }
+        Uuid::parse_str(&s[start..])
+            .map(PublicId)
+            .map_err(|_| invalid())
+    }
+}
+
+impl<'de> Deserialize<'de> for PublicId {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// The row id of the how-to, or `NotFound`.
+pub async fn resolve(
+    db_pool: &PgPool,
+    public_id: PublicId,
+) -> Result<i32, ServerError> {
+    let (id,): (i32,) =
+        sqlx::query_as("SELECT id FROM howto WHERE public_id = $1")
+            .bind(public_id.0)
+            .fetch_optional(db_pool)
+            .await?
+            .ok_or(ServerError::NotFound)?;
+    Ok(id)
+}
+
+/// `morning-chicken-chores-<uuid>` for "Morning Chicken Chores 🐓".
+pub fn path(title: &str, public_id: Uuid) -> String {
+    let mut slug = String::new();
+    for c in title.chars().flat_map(char::to_lowercase) {
+        if slug.len() >= SLUG_CHARS {
+            break;
+        }
+        if c.is_ascii_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    if !slug.is_empty() && !slug.ends_with('-') {
+        slug.push('-');
+    }
+    format!("{}{}", slug, public_id.to_hyphenated())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+
+    fn parse(s: &str) -> Option<Uuid> {
+        s.parse::<PublicId>().ok().map(|id| id.0)
+    }
+
+    #[test]
+    fn bare_uuid() {
+        assert_eq!(parse(ID), Some(Uuid::parse_str(ID).unwrap()));
+    }
+
+    #[test]
+    fn slug_then_uuid() {
+        let id = Uuid::parse_str(ID).unwrap();
+        assert_eq!(parse(&format!("morning-chores-{}", ID)), Some(id));
+        // A stale slug still works.
+        assert_eq!(parse(&format!("old-title-{}", ID)), Some(id));
+    }
+
+    #[test]
+    fn wrong_separator() {
+        assert_eq!(parse(&format!("morning-chores_{}", ID)), None);
+        assert_eq!(parse(&format!("morning-chores{}", ID)), None);
+    }
+
+    #[test]
+    fn shorter_than_a_uuid() {
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("42"), None);
+        assert_eq!(parse(&ID[1..]), None);
+    }
+
+    #[test]
+    fn multibyte_before_the_uuid() {
+        // The uuid would start inside the 🐓.
+        assert_eq!(parse(&format!("🐓{}", &ID[2..])), None);
+        assert!(parse(&format!("é-{}", ID)).is_some());
+    }
+
+    #[test]
+    fn path_slugs_the_title() {
+        let id = Uuid::parse_str(ID).unwrap();
+        assert_eq!(
+            path("Morning Chicken Chores 🐓", id),
+            format!("morning-chicken-chores-{}", ID)
+        );
+        assert_eq!(path("", id), ID);
+        assert_eq!(path("🐓🐓", id), ID);
+        assert_eq!(path("Crème brûlée", id), format!("cr-me-br-l-e-{}", ID));
+    }
+
+    #[test]
+    fn path_slug_is_capped() {
+        let id = Uuid::parse_str(ID).unwrap();
+        let title = "ü".repeat(40) + &"a".repeat(100);
+        let p = path(&title, id);
+        assert!(p.len() <= SLUG_CHARS + 1 + UUID_LEN);
+        assert_eq!(parse(&p), Some(id));
+    }
+}
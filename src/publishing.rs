@@ -0,0 +1,81 @@
+//! How-tos start out as drafts, so authors can add steps one upload at a
+//! time before anyone else sees them.
+
+use crate::{
+    auth::CurrentUser,
+    public_id::{self, PublicId},
+    visibility, ServerError,
+};
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+use uuid::Uuid;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PublishData {
+    id: Uuid,
+    published_at: DateTime<Utc>,
+}
+
+/// Publishes a complete how-to: one with at least one step, all of them
+/// titled. Publishing again keeps the first `published_at`.
+pub async fn publish(
+    user: CurrentUser,
+    db_pool: web::Data<PgPool>,
+    web::Path(public_id): web::Path<PublicId>,
+) -> Result<HttpResponse, ServerError> {
+    let id = public_id::resolve(&db_pool, public_id).await?;
+    visibility::check_author(&db_pool, id, &user).await?;
+
+    let mut tx = db_pool.begin().await?;
+    // Locked, so steps can't be removed between checking and publishing.
+    sqlx::query("SELECT 1 FROM howto WHERE id = $1 FOR UPDATE")
+        .bind(id)
+        .execute(&mut tx)
+        .await?;
+    let titles: Vec<(String,)> = sqlx::query_as(
+        r#"
+SELECT step.title
+FROM step, howto_step
+WHERE howto_step.howto_id = $1
+AND howto_step.step_id = step.id
+ORDER BY howto_step.position, step.id
+"#,
+    )
+    .bind(id)
+    .fetch_all(&mut tx)
+    .await?;
+
+    if titles.is_empty() {
+        return Err(ServerError::ValidationError {
+            field: "steps",
+            message: "Add at least one step before publishing".into(),
+        });
+    }
+    if let Some(n) = titles.iter().position(|(t,)| t.trim().is_empty()) {
+        return Err(ServerError::ValidationError {
+            field: "steps",
+            message: format!("Step {} needs a title", n + 1),
+        });
+    }
+
+    let (published_at,): (DateTime<Utc>,) = sqlx::query_as(
+        r#"
+UPDATE howto
+SET published_at = COALESCE(published_at, now())
+WHERE id = $1
+RETURNING published_at
+"#,
+    )
+    .bind(id)
+    .fetch_one(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(PublishData {
+        id: public_id.0,
+        published_at,
+    }))
+}
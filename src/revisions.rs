@@ -0,0 +1,664 @@
+//! Every change to a how-to's title, steps, step order or media is kept as a
+//! numbered revision holding a full copy of the how-to as it was afterwards.
+//! Revisions are never changed. Restoring an old one adds a new revision on
+//! top, so nothing is lost by restoring either.
+
+use crate::{
+    auth::CurrentUser,
+    media::MediaType,
+    pagination::{Page, PageQuery},
+    public_id::{self, PublicId},
+    users::UserSummary,
+    visibility, ServerError,
+};
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    postgres::{PgPool, Postgres},
+    types::Json,
+    Transaction,
+};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename = "howto_change", rename_all = "snake_case")]
+pub enum Change {
+    Create,
+    Title,
+    StepAdded,
+    StepRemoved,
+    StepUpdated,
+    Restore,
+}
+
+/// The how-to as it was at one revision. Built by `record`, so the field
+/// names have to match the JSON it writes.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    title: String,
+    steps: Vec<SnapshotStep>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotStep {
+    step_id: i32,
+    title: String,
+    seconds: i32,
+    position: i32,
+    media: Vec<SnapshotMedia>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotMedia {
+    filename: String,
+    media_type: MediaType,
+    position: i32,
+}
+
+/// Adds a revision with the how-to as it is now in `tx`, and returns its
+/// number, which is also the how-to's new version. Call it after the change,
+/// in the same transaction.
+pub async fn record(
+    tx: &mut Transaction<'_, Postgres>,
+    how_to_id: i32,
+    author_id: Option<i32>,
+    change: Change,
+    restored_from: Option<i32>,
+) -> Result<i32, sqlx::Error> {
+    // Locked, so two changes at once can't take the same number.
+    sqlx::query("SELECT 1 FROM howto WHERE id = $1 FOR UPDATE")
+        .bind(how_to_id)
+        .execute(&mut *tx)
+        .await?;
+    // The same snapshot as the backfill in V11__revisions.sql.
+    let (number,): (i32,) = sqlx::query_as(
+        r#"
+INSERT INTO howto_revision
+    (howto_id, number, change, restored_from, author_id, snapshot)
+SELECT
+    howto.id,
+    COALESCE((
+        SELECT MAX(number) FROM howto_revision WHERE howto_id = howto.id
+    ), 0) + 1,
+    $2,
+    $3,
+    $4,
+    jsonb_build_object(
+        'title', howto.title,
+        'steps', COALESCE((
+            SELECT jsonb_agg(
+                jsonb_build_object(
+                    'stepId', step.id,
+                    'title', step.title,
+                    'seconds', step.seconds,
+                    'position', howto_step.position,
+                    'media', COALESCE((
+                        SELECT jsonb_agg(
+                            jsonb_build_object(
+                                'filename', step_media.filename,
+                                'mediaType', step_media.media_type,
+                                'position', step_media.position
+                            )
+                            ORDER BY step_media.position
+                        )
+                        FROM step_media
+                        WHERE step_media.step_id = step.id
+                    ), '[]')
+                )
+                ORDER BY howto_step.position, step.id
+            )
+            FROM howto_step, step
+            WHERE howto_step.howto_id = howto.id
+            AND step.id = howto_step.step_id
+        ), '[]')
+    )
+FROM howto
+WHERE howto.id = $1
+RETURNING number
+"#,
+    )
+    .bind(how_to_id)
+    .bind(change)
+    .bind(restored_from)
+    .bind(author_id)
+    .fetch_one(&mut *tx)
+    .await?;
+    sqlx::query("UPDATE howto SET version = $2 WHERE id = $1")
+        .bind(how_to_id)
+        .bind(number)
+        .execute(&mut *tx)
+        .await?;
+    Ok(number)
+}
+
+/// The files no step and no revision refers to any more, which are safe to
+/// remove. Forks share files with the how-to they came from, and files of
+/// deleted steps are kept while a revision can restore them.
+pub async fn unreferenced(
+    tx: &mut Transaction<'_, Postgres>,
+    filenames: Vec<String>,
+) -> Result<Vec<String>, sqlx::Error> {
+    let rows: Vec<(String,)> = sqlx::query_as(
+        r#"
+SELECT f.name
+FROM UNNEST($1::text[]) AS f(name)
+WHERE NOT EXISTS (
+    SELECT 1 FROM step_media WHERE step_media.filename = f.name
+)
+AND NOT EXISTS (
+    SELECT 1
+    FROM howto_revision,
+        jsonb_array_elements(howto_revision.snapshot->'steps') AS s(step),
+        jsonb_array_elements(s.step->'media') AS m(media)
+    WHERE m.media->>'filename' = f.name
+)
+"#,
+    )
+    .bind(filenames)
+    .fetch_all(&mut *tx)
+    .await?;
+    Ok(rows.into_iter().map(|(name,)| name).collect())
+}
+
+#[derive(sqlx::FromRow)]
+struct RevisionRow {
+    number: i32,
+    change: Change,
+    restored_from: Option<i32>,
+    created_at: DateTime<Utc>,
+    author_id: Option<i32>,
+    author_display_name: Option<String>,
+    author_avatar_filename: Option<String>,
+    snapshot: Json<Snapshot>,
+}
+
+const REVISION_COLUMNS: &str = r#"
+SELECT
+    howto_revision.number,
+    howto_revision.change,
+    howto_revision.restored_from,
+    howto_revision.created_at,
+    app_user.id AS author_id,
+    app_user.display_name AS author_display_name,
+    app_user.avatar_filename AS author_avatar_filename,
+    howto_revision.snapshot
+FROM howto_revision
+LEFT JOIN app_user ON app_user.id = howto_revision.author_id
+"#;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RevisionSummary {
+    number: i32,
+    change: Change,
+    restored_from: Option<i32>,
+    created_at: DateTime<Utc>,
+    /// Who made the change, if they were logged in.
+    author: Option<UserSummary>,
+    title: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Revision {
+    #[serde(flatten)]
+    summary: RevisionSummary,
+    steps: Vec<SnapshotStep>,
+}
+
+impl RevisionRow {
+    fn into_revision(self) -> Revision {
+        let author = match (self.author_id, self.author_display_name) {
+            (Some(id), Some(display_name)) => Some(UserSummary {
+                id,
+                display_name,
+                avatar_filename: self.author_avatar_filename,
+            }),
+            _ => None,
+        };
+        let Snapshot { title, steps } = self.snapshot.0;
+        Revision {
+            summary: RevisionSummary {
+                number: self.number,
+                change: self.change,
+                restored_from: self.restored_from,
+                created_at: self.created_at,
+                author,
+                title,
+            },
+            steps,
+        }
+    }
+}
+
+async fn fetch(
+    db_pool: &PgPool,
+    how_to_id: i32,
+    number: i32,
+) -> Result<Revision, ServerError> {
+    let query = format!(
+        "{} WHERE howto_revision.howto_id = $1 AND howto_revision.number = $2",
+        REVISION_COLUMNS
+    );
+    let row: RevisionRow = sqlx::query_as(&query)
+        .bind(how_to_id)
+        .bind(number)
+        .fetch_optional(db_pool)
+        .await?
+        .ok_or(ServerError::NotFound)?;
+    Ok(row.into_revision())
+}
+
+/// Newest first. Anyone who can read the how-to can read its history.
+pub async fn list(
+    db_pool: web::Data<PgPool>,
+    web::Path(public_id): web::Path<PublicId>,
+    page: web::Query<PageQuery>,
+    user: Option<CurrentUser>,
+) -> Result<HttpResponse, ServerError> {
+    let id = public_id::resolve(&db_pool, public_id).await?;
+    visibility::check(&db_pool, id, user.as_ref()).await?;
+
+    let query = format!(
+        r#"{}
+WHERE howto_revision.howto_id = $1
+ORDER BY howto_revision.number DESC
+LIMIT $2 OFFSET $3
+"#,
+        REVISION_COLUMNS
+    );
+    let rows: Vec<RevisionRow> = sqlx::query_as(&query)
+        .bind(id)
+        .bind(page.fetch_limit())
+        .bind(page.offset())
+        .fetch_all(&**db_pool)
+        .await?;
+    let revisions: Page<RevisionSummary> = page.page(
+        rows.into_iter()
+            .map(|row| row.into_revision().summary)
+            .collect(),
+    );
+    Ok(HttpResponse::Ok().json(revisions))
+}
+
+pub async fn get(
+    db_pool: web::Data<PgPool>,
+    web::Path((public_id, number)): web::Path<(PublicId, i32)>,
+    user: Option<CurrentUser>,
+) -> Result<HttpResponse, ServerError> {
+    let id = public_id::resolve(&db_pool, public_id).await?;
+    visibility::check(&db_pool, id, user.as_ref()).await?;
+    Ok(HttpResponse::Ok().json(fetch(&db_pool, id, number).await?))
+}
+
+#[derive(Deserialize)]
+pub struct DiffQuery {
+    from: i32,
+    to: i32,
+}
+
+#[derive(Serialize)]
+struct TitleChange {
+    from: String,
+    to: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StepChange {
+    step_id: i32,
+    from: SnapshotStep,
+    to: SnapshotStep,
+}
+
+/// Step ids in order, before and after.
+#[derive(Serialize)]
+struct OrderChange {
+    from: Vec<i32>,
+    to: Vec<i32>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SnapshotDiff {
+    from: i32,
+    to: i32,
+    title: Option<TitleChange>,
+    added: Vec<SnapshotStep>,
+    removed: Vec<SnapshotStep>,
+    /// Steps whose title, duration or media changed.
+    changed: Vec<StepChange>,
+    /// Set when the steps both revisions have are in a different order.
+    order: Option<OrderChange>,
+}
+
+/// Steps are matched by id. Restoring keeps step ids, so a step that was
+/// deleted and restored is the same step on both sides.
+fn diff(from: &Revision, to: &Revision) -> SnapshotDiff {
+    let before: HashMap<i32, &SnapshotStep> =
+        from.steps.iter().map(|s| (s.step_id, s)).collect();
+    let after: HashMap<i32, &SnapshotStep> =
+        to.steps.iter().map(|s| (s.step_id, s)).collect();
+
+    let added = to
+        .steps
+        .iter()
+        .filter(|s| !before.contains_key(&s.step_id))
+        .cloned()
+        .collect();
+    let removed = from
+        .steps
+        .iter()
+        .filter(|s| !after.contains_key(&s.step_id))
+        .cloned()
+        .collect();
+    let changed = to
+        .steps
+        .iter()
+        .filter_map(|new| {
+            let old = before.get(&new.step_id)?;
+            let same = old.title == new.title
+                && old.seconds == new.seconds
+                && old.media == new.media;
+            if same {
+                return None;
+            }
+            Some(StepChange {
+                step_id: new.step_id,
+                from: (*old).clone(),
+                to: new.clone(),
+            })
+        })
+        .collect();
+
+    let kept_before: Vec<i32> = from
+        .steps
+        .iter()
+        .map(|s| s.step_id)
+        .filter(|id| after.contains_key(id))
+        .collect();
+    let kept_after: Vec<i32> = to
+        .steps
+        .iter()
+        .map(|s| s.step_id)
+        .filter(|id| before.contains_key(id))
+        .collect();
+    let order = if kept_before != kept_after {
+        Some(OrderChange {
+            from: from.steps.iter().map(|s| s.step_id).collect(),
+            to: to.steps.iter().map(|s| s.step_id).collect(),
+        })
+    } else {
+        None
+    };
+
+    let title = if from.summary.title != to.summary.title {
+        Some(TitleChange {
+            from: from.summary.title.clone(),
+            to: to.summary.title.clone(),
+        })
+    } else {
+        None
+    };
+
+    SnapshotDiff {
+        from: from.summary.number,
+        to: to.summary.number,
+        title,
+        added,
+        removed,
+        changed,
+        order,
+    }
+}
+
+/// `?from=&to=` revision numbers. `from` may be the later one, which shows
+/// what undoing the changes would do.
+pub async fn compare(
+    db_pool: web::Data<PgPool>,
+    web::Path(public_id): web::Path<PublicId>,
+    query: web::Query<DiffQuery>,
+    user: Option<CurrentUser>,
+) -> Result<HttpResponse, ServerError> {
+    let id = public_id::resolve(&db_pool, public_id).await?;
+    visibility::check(&db_pool, id, user.as_ref()).await?;
+    let from = fetch(&db_pool, id, query.from).await?;
+    let to = fetch(&db_pool, id, query.to).await?;
+    Ok(HttpResponse::Ok().json(diff(&from, &to)))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RestoreData {
+    id: Uuid,
+    /// The new revision the restore was recorded as.
+    revision: i32,
+    restored_from: i32,
+}
+
+/// Puts the title, steps, order and media back the way they were at
+/// `number`, all at once or not at all. Only the author may restore.
+pub async fn restore(
+    user: CurrentUser,
+    db_pool: web::Data<PgPool>,
+    web::Path((public_id, number)): web::Path<(PublicId, i32)>,
+) -> Result<HttpResponse, ServerError> {
+    let id = public_id::resolve(&db_pool, public_id).await?;
+    visibility::check_author(&db_pool, id, &user).await?;
+
+    let mut tx = db_pool.begin().await?;
+    sqlx::query("SELECT 1 FROM howto WHERE id = $1 FOR UPDATE")
+        .bind(id)
+        .execute(&mut tx)
+        .await?;
+    let (snapshot,): (Json<Snapshot>,) = sqlx::query_as(
+        r#"
+SELECT snapshot
+FROM howto_revision
+WHERE howto_id = $1
+AND number = $2
+"#,
+    )
+    .bind(id)
+    .bind(number)
+    .fetch_optional(&mut tx)
+    .await?
+    .ok_or(ServerError::NotFound)?;
+    let Snapshot { title, steps } = snapshot.0;
+
+    sqlx::query("UPDATE howto SET title = $2 WHERE id = $1")
+        .bind(id)
+        .bind(&title)
+        .execute(&mut tx)
+        .await?;
+
+    let current: Vec<(i32,)> = sqlx::query_as(
+        "DELETE FROM howto_step WHERE howto_id = $1 RETURNING step_id",
+    )
+    .bind(id)
+    .fetch_all(&mut tx)
+    .await?;
+
+    for step in &steps {
+        // Steps deleted since are put back under their old id, which the
+        // identity column never hands out again.
+        sqlx::query(
+            r#"
+INSERT INTO step (id, title, seconds)
+OVERRIDING SYSTEM VALUE
+VALUES ($1, $2, $3)
+ON CONFLICT (id) DO UPDATE
+SET title = EXCLUDED.title, seconds = EXCLUDED.seconds
+"#,
+        )
+        .bind(step.step_id)
+        .bind(&step.title)
+        .bind(step.seconds)
+        .execute(&mut tx)
+        .await?;
+        sqlx::query("DELETE FROM step_media WHERE step_id = $1")
+            .bind(step.step_id)
+            .execute(&mut tx)
+            .await?;
+        for media in &step.media {
+            sqlx::query(
+                r#"
+INSERT INTO step_media (step_id, position, filename, media_type)
+VALUES ($1, $2, $3, $4)
+"#,
+            )
+            .bind(step.step_id)
+            .bind(media.position)
+            .bind(&media.filename)
+            .bind(media.media_type)
+            .execute(&mut tx)
+            .await?;
+        }
+        sqlx::query(
+            r#"
+INSERT INTO howto_step (howto_id, step_id, position)
+VALUES ($1, $2, $3)
+"#,
+        )
+        .bind(id)
+        .bind(step.step_id)
+        .bind(step.position)
+        .execute(&mut tx)
+        .await?;
+    }
+
+    // Steps the revision didn't have go, media rows with them. Their files
+    // stay, since this revision's history still refers to them.
+    let current: Vec<i32> = current.into_iter().map(|(id,)| id).collect();
+    sqlx::query(
+        r#"
+DELETE FROM step
+WHERE id = ANY($1)
+AND NOT EXISTS (SELECT 1 FROM howto_step WHERE howto_step.step_id = step.id)
+"#,
+    )
+    .bind(current)
+    .execute(&mut tx)
+    .await?;
+
+    let revision =
+        record(&mut tx, id, Some(user.0), Change::Restore, Some(number))
+            .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(RestoreData {
+        id: public_id.0,
+        revision,
+        restored_from: number,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(step_id: i32, title: &str) -> SnapshotStep {
+        SnapshotStep {
+            step_id,
+            title: title.into(),
+            seconds: 0,
+            position: 0,
+            media: vec![],
+        }
+    }
+
+    fn revision(
+        number: i32,
+        title: &str,
+        steps: Vec<SnapshotStep>,
+    ) -> Revision {
+        Revision {
+            summary: RevisionSummary {
+                number,
+                change: Change::StepUpdated,
+                restored_from: None,
+                created_at: Utc::now(),
+                author: None,
+                title: title.into(),
+            },
+            steps,
+        }
+    }
+
+    fn ids(steps: &[SnapshotStep]) -> Vec<i32> {
+        steps.iter().map(|s| s.step_id).collect()
+    }
+
+    #[test]
+    fn same_revision_has_no_changes() {
+        let a = revision(1, "Tea", vec![step(1, "Boil"), step(2, "Pour")]);
+        let d = diff(&a, &a);
+        assert_eq!((d.from, d.to), (1, 1));
+        assert!(d.title.is_none());
+        assert!(d.added.is_empty() && d.removed.is_empty());
+        assert!(d.changed.is_empty());
+        assert!(d.order.is_none());
+    }
+
+    #[test]
+    fn title_change() {
+        let a = revision(1, "Tea", vec![]);
+        let b = revision(2, "Green tea", vec![]);
+        let title = diff(&a, &b).title.unwrap();
+        assert_eq!(
+            (title.from.as_str(), title.to.as_str()),
+            ("Tea", "Green tea")
+        );
+    }
+
+    #[test]
+    fn added_removed_and_changed_steps() {
+        let a = revision(
+            1,
+            "Tea",
+            vec![step(1, "Boil"), step(2, "Pour"), step(3, "Wait")],
+        );
+        let mut longer = step(3, "Wait");
+        longer.seconds = 180;
+        let b =
+            revision(2, "Tea", vec![step(1, "Boil"), longer, step(4, "Drink")]);
+        let d = diff(&a, &b);
+        assert_eq!(ids(&d.added), [4]);
+        assert_eq!(ids(&d.removed), [2]);
+        assert_eq!(d.changed.len(), 1);
+        assert_eq!(d.changed[0].step_id, 3);
+        assert_eq!(d.changed[0].from.seconds, 0);
+        assert_eq!(d.changed[0].to.seconds, 180);
+        // Dropping a step doesn't reorder the rest.
+        assert!(d.order.is_none());
+    }
+
+    #[test]
+    fn media_change() {
+        let a = revision(1, "Tea", vec![step(1, "Boil")]);
+        let mut with_photo = step(1, "Boil");
+        with_photo.media.push(SnapshotMedia {
+            filename: "abc.jpg".into(),
+            media_type: MediaType::Image,
+            position: 0,
+        });
+        let b = revision(2, "Tea", vec![with_photo]);
+        assert_eq!(diff(&a, &b).changed.len(), 1);
+    }
+
+    #[test]
+    fn reordered_steps() {
+        let a = revision(1, "Tea", vec![step(1, "Boil"), step(2, "Pour")]);
+        let b = revision(2, "Tea", vec![step(2, "Pour"), step(1, "Boil")]);
+        let d = diff(&a, &b);
+        let order = d.order.unwrap();
+        assert_eq!((order.from, order.to), (vec![1, 2], vec![2, 1]));
+        assert!(d.changed.is_empty());
+    }
+}
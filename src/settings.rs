@@ -0,0 +1,623 @@
+//! Server configuration. Values come from an optional TOML file, then from
+//! environment variables (including `.env`), then from the defaults below.
+//! Everything is checked before the server starts.
+
+use crate::ServerSetupError;
+use serde::Deserialize;
+use std::{
+    env::{self, VarError},
+    fmt::Display,
+    fs,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Where to look for the config file when `HOWIDO_CONFIG` isn't set. It's
+/// fine for it not to exist.
+const DEFAULT_CONFIG_FILE: &str = "howido.toml";
+
+/// What the server is deployed as. This decides how open CORS is.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Environment {
+    Development,
+    Staging,
+    Production,
+}
+
+impl FromStr for Environment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "development" => Ok(Environment::Development),
+            "staging" => Ok(Environment::Staging),
+            "production" => Ok(Environment::Production),
+            _ => Err(format!(
+                "{} is not one of development, staging, production",
+                s
+            )),
+        }
+    }
+}
+
+/// Where JSON log lines go.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutput {
+    Stdout,
+    Syslog,
+}
+
+impl FromStr for LogOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stdout" => Ok(LogOutput::Stdout),
+            "syslog" => Ok(LogOutput::Syslog),
+            _ => Err(format!("{} is not one of stdout, syslog", s)),
+        }
+    }
+}
+
+/// How account emails are sent.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    /// Written to the log, for development.
+    Console,
+    /// Written to files in `mail_dir`, for development and tests.
+    File,
+    Smtp,
+}
+
+impl FromStr for MailTransport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "console" => Ok(MailTransport::Console),
+            "file" => Ok(MailTransport::File),
+            "smtp" => Ok(MailTransport::Smtp),
+            _ => Err(format!("{} is not one of console, file, smtp", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub environment: Environment,
+    pub database_uri: String,
+    pub bind_address: IpAddr,
+    pub port: u16,
+    /// Uploaded media is written to and served from here.
+    pub storage_root: PathBuf,
+    pub max_image_bytes: usize,
+    pub max_video_bytes: usize,
+    pub max_video_seconds: u64,
+    /// Origins allowed to call the API from a browser, in staging. Development
+    /// allows any origin and production allows none.
+    pub cors_origins: Vec<String>,
+    /// Let browsers send cookies with cross-origin requests.
+    pub cors_allow_credentials: bool,
+    /// An `env_logger` filter. `RUST_LOG` takes precedence when it's set.
+    pub log_level: String,
+    pub log_output: LogOutput,
+    pub pool_size: u32,
+    /// Connections kept open even when idle.
+    pub pool_min_connections: u32,
+    /// How long a request waits for a free connection before failing.
+    pub pool_connect_timeout_seconds: u64,
+    /// Idle connections above `pool_min_connections` are closed after this.
+    pub pool_idle_timeout_seconds: u64,
+    /// Postgres cancels any statement running longer than this. 0 disables it.
+    pub statement_timeout_ms: u64,
+    /// How long startup keeps retrying while the database isn't reachable.
+    pub db_startup_timeout_seconds: u64,
+    /// How long in-flight requests get to finish after SIGTERM/SIGINT.
+    pub shutdown_timeout_seconds: u64,
+    /// PEM files. Setting both serves HTTPS on `port`.
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    /// With TLS on, also listen for plain HTTP here and redirect to HTTPS.
+    pub http_redirect_port: Option<u16>,
+    /// Signs the login cookie. At least 32 bytes, and the same on every
+    /// instance. Unset, a random one is made at startup, so restarting logs
+    /// everyone out.
+    pub session_secret: Option<String>,
+    /// Where the client is reachable, for links in emails.
+    pub public_url: String,
+    pub mail_transport: MailTransport,
+    /// `Name <address>` that emails are sent from.
+    pub mail_from: String,
+    pub mail_dir: PathBuf,
+    /// STARTTLS is always used.
+    pub smtp_host: Option<String>,
+    pub smtp_port: u16,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            environment: Environment::Production,
+            database_uri: String::new(),
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 80,
+            storage_root: PathBuf::from("./tmp"),
+            max_image_bytes: 10 * 1024 * 1024,
+            max_video_bytes: 50 * 1024 * 1024,
+            max_video_seconds: 60,
+            cors_origins: Vec::new(),
+            cors_allow_credentials: false,
+            log_level: "actix_web=info,debug".into(),
+            log_output: LogOutput::Stdout,
+            pool_size: 10,
+            pool_min_connections: 0,
+            pool_connect_timeout_seconds: 30,
+            pool_idle_timeout_seconds: 600,
+            statement_timeout_ms: 30_000,
+            db_startup_timeout_seconds: 60,
+            shutdown_timeout_seconds: 30,
+            tls_cert_path: None,
+            tls_key_path: None,
+            http_redirect_port: None,
+            session_secret: None,
+            public_url: "http://localhost".into(),
+            mail_transport: MailTransport::Console,
+            mail_from: "howido <noreply@localhost>".into(),
+            mail_dir: PathBuf::from("./tmp/mail"),
+            smtp_host: None,
+            smtp_port: 587,
+            smtp_username: None,
+            smtp_password: None,
+        }
+    }
+}
+
+impl Settings {
+    pub(crate) fn load() -> Result<Self, ServerSetupError> {
+        let (path, required) = match env::var("HOWIDO_CONFIG") {
+            Ok(path) => (PathBuf::from(path), true),
+            Err(VarError::NotPresent) => {
+                (PathBuf::from(DEFAULT_CONFIG_FILE), false)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut settings = if required || path.exists() {
+            Self::from_file(&path)?
+        } else {
+            Settings::default()
+        };
+        settings.apply_env()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, ServerSetupError> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            ServerSetupError::ReadConfigFile(path.to_path_buf(), e)
+        })?;
+        toml::from_str(&contents).map_err(|e| {
+            ServerSetupError::ParseConfigFile(path.to_path_buf(), e)
+        })
+    }
+
+    fn apply_env(&mut self) -> Result<(), ServerSetupError> {
+        env_override("ENVIRONMENT", &mut self.environment)?;
+        env_override("DATABASE_URI", &mut self.database_uri)?;
+        env_override("BIND_ADDRESS", &mut self.bind_address)?;
+        env_override("PORT", &mut self.port)?;
+        env_override("STORAGE_ROOT", &mut self.storage_root)?;
+        env_override("MAX_IMAGE_BYTES", &mut self.max_image_bytes)?;
+        env_override("MAX_VIDEO_BYTES", &mut self.max_video_bytes)?;
+        env_override("MAX_VIDEO_SECONDS", &mut self.max_video_seconds)?;
+        env_override(
+            "CORS_ALLOW_CREDENTIALS",
+            &mut self.cors_allow_credentials,
+        )?;
+        env_override("LOG_LEVEL", &mut self.log_level)?;
+        env_override("RUST_LOG", &mut self.log_level)?;
+        env_override("LOG_OUTPUT", &mut self.log_output)?;
+        env_override("POOL_SIZE", &mut self.pool_size)?;
+        env_override("POOL_MIN_CONNECTIONS", &mut self.pool_min_connections)?;
+        env_override(
+            "POOL_CONNECT_TIMEOUT_SECONDS",
+            &mut self.pool_connect_timeout_seconds,
+        )?;
+        env_override(
+            "POOL_IDLE_TIMEOUT_SECONDS",
+            &mut self.pool_idle_timeout_seconds,
+        )?;
+        env_override("STATEMENT_TIMEOUT_MS", &mut self.statement_timeout_ms)?;
+        env_override(
+            "DB_STARTUP_TIMEOUT_SECONDS",
+            &mut self.db_startup_timeout_seconds,
+        )?;
+        env_override(
+            "SHUTDOWN_TIMEOUT_SECONDS",
+            &mut self.shutdown_timeout_seconds,
+        )?;
+        env_override_optional("TLS_CERT_PATH", &mut self.tls_cert_path)?;
+        env_override_optional("TLS_KEY_PATH", &mut self.tls_key_path)?;
+        env_override_optional(
+            "HTTP_REDIRECT_PORT",
+            &mut self.http_redirect_port,
+        )?;
+        env_override_optional("SESSION_SECRET", &mut self.session_secret)?;
+        env_override("PUBLIC_URL", &mut self.public_url)?;
+        env_override("MAIL_TRANSPORT", &mut self.mail_transport)?;
+        env_override("MAIL_FROM", &mut self.mail_from)?;
+        env_override("MAIL_DIR", &mut self.mail_dir)?;
+        env_override_optional("SMTP_HOST", &mut self.smtp_host)?;
+        env_override("SMTP_PORT", &mut self.smtp_port)?;
+        env_override_optional("SMTP_USERNAME", &mut self.smtp_username)?;
+        env_override_optional("SMTP_PASSWORD", &mut self.smtp_password)?;
+
+        let mut cors_origins = String::new();
+        env_override("CORS_ORIGINS", &mut cors_origins)?;
+        if !cors_origins.is_empty() {
+            self.cors_origins = cors_origins
+                .split(',')
+                .map(|origin| origin.trim().to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect();
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ServerSetupError> {
+        if self.database_uri.is_empty() {
+            return Err(invalid("database_uri", "must be set"));
+        }
+        if self.port == 0 {
+            return Err(invalid("port", "must not be 0"));
+        }
+        if self.pool_size == 0 {
+            return Err(invalid("pool_size", "must be at least 1"));
+        }
+        if self.pool_min_connections > self.pool_size {
+            return Err(invalid(
+                "pool_min_connections",
+                "must not be more than pool_size",
+            ));
+        }
+        if self.pool_connect_timeout_seconds == 0 {
+            return Err(invalid(
+                "pool_connect_timeout_seconds",
+                "must be at least 1",
+            ));
+        }
+        if self.max_image_bytes == 0 || self.max_video_bytes == 0 {
+            return Err(invalid("max_*_bytes", "must be at least 1"));
+        }
+        if self.max_video_seconds == 0 {
+            return Err(invalid("max_video_seconds", "must be at least 1"));
+        }
+        match self.environment {
+            Environment::Staging if self.cors_origins.is_empty() => {
+                return Err(invalid("cors_origins", "must be set in staging"))
+            }
+            Environment::Development | Environment::Production
+                if !self.cors_origins.is_empty() =>
+            {
+                return Err(invalid("cors_origins", "is only used in staging"))
+            }
+            _ => (),
+        }
+        if let Some(origin) = self
+            .cors_origins
+            .iter()
+            .find(|o| !(o.starts_with("http://") || o.starts_with("https://")))
+        {
+            return Err(invalid(
+                "cors_origins",
+                format!("{} must start with http:// or https://", origin),
+            ));
+        }
+        match (&self.tls_cert_path, &self.tls_key_path) {
+            (Some(_), None) => {
+                return Err(invalid("tls_key_path", "must be set with a cert"))
+            }
+            (None, Some(_)) => {
+                return Err(invalid("tls_cert_path", "must be set with a key"))
+            }
+            _ => (),
+        }
+        if let Some(redirect_port) = self.http_redirect_port {
+            if self.tls_cert_path.is_none() {
+                return Err(invalid("http_redirect_port", "needs TLS set up"));
+            }
+            if redirect_port == self.port {
+                return Err(invalid("http_redirect_port", "must not be port"));
+            }
+        }
+        if matches!(&self.session_secret, Some(s) if s.len() < 32) {
+            return Err(invalid("session_secret", "must be at least 32 bytes"));
+        }
+        if !(self.public_url.starts_with("http://")
+            || self.public_url.starts_with("https://"))
+        {
+            return Err(invalid(
+                "public_url",
+                "must start with http:// or https://",
+            ));
+        }
+        if self.mail_transport == MailTransport::Smtp {
+            if self.smtp_host.is_none() {
+                return Err(invalid("smtp_host", "must be set to use smtp"));
+            }
+            if self.smtp_username.is_some() != self.smtp_password.is_some() {
+                return Err(invalid(
+                    "smtp_username",
+                    "must be set along with smtp_password",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn max_video_duration(&self) -> Duration {
+        Duration::from_secs(self.max_video_seconds)
+    }
+
+    /// Whether browsers reach this over HTTPS, either served here or by a
+    /// proxy in front of `public_url`.
+    pub fn is_https(&self) -> bool {
+        self.tls_cert_path.is_some() || self.public_url.starts_with("https://")
+    }
+}
+
+fn env_override<T>(
+    name: &'static str,
+    target: &mut T,
+) -> Result<(), ServerSetupError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match env::var(name) {
+        Ok(value) => {
+            *target = value.parse().map_err(|e| invalid(name, e))?;
+            Ok(())
+        }
+        Err(VarError::NotPresent) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn env_override_optional<T>(
+    name: &'static str,
+    target: &mut Option<T>,
+) -> Result<(), ServerSetupError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match env::var(name) {
+        Ok(value) => {
+            *target = Some(value.parse().map_err(|e| invalid(name, e))?);
+            Ok(())
+        }
+        Err(VarError::NotPresent) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn invalid(name: &'static str, message: impl Display) -> ServerSetupError {
+    ServerSetupError::InvalidSetting {
+        name,
+        message: message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn valid() -> Settings {
+        Settings {
+            database_uri: "postgresql://localhost/howido".into(),
+            ..Settings::default()
+        }
+    }
+
+    /// The name of the setting `validate` complains about.
+    fn rejected(settings: Settings) -> &'static str {
+        match settings.validate() {
+            Err(ServerSetupError::InvalidSetting { name, .. }) => name,
+            other => panic!("expected an invalid setting, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn defaults_need_only_a_database() {
+        assert!(valid().validate().is_ok());
+        assert_eq!(rejected(Settings::default()), "database_uri");
+    }
+
+    #[test]
+    fn zero_limits() {
+        assert_eq!(rejected(Settings { port: 0, ..valid() }), "port");
+        assert_eq!(
+            rejected(Settings {
+                pool_size: 0,
+                ..valid()
+            }),
+            "pool_size"
+        );
+        assert_eq!(
+            rejected(Settings {
+                max_video_bytes: 0,
+                ..valid()
+            }),
+            "max_*_bytes"
+        );
+        assert_eq!(
+            rejected(Settings {
+                max_video_seconds: 0,
+                ..valid()
+            }),
+            "max_video_seconds"
+        );
+    }
+
+    #[test]
+    fn cors_origins_need_a_scheme() {
+        let settings = Settings {
+            environment: Environment::Staging,
+            cors_origins: vec!["example.com".into()],
+            ..valid()
+        };
+        assert_eq!(rejected(settings), "cors_origins");
+    }
+
+    #[test]
+    fn file_fills_in_defaults() {
+        let settings: Settings =
+            toml::from_str("port = 8080\nmax_video_seconds = 30").unwrap();
+        assert_eq!(settings.port, 8080);
+        assert_eq!(settings.max_video_seconds, 30);
+        assert_eq!(settings.pool_size, Settings::default().pool_size);
+        assert!(toml::from_str::<Settings>("prot = 8080").is_err());
+    }
+
+    #[test]
+    fn tls_needs_cert_and_key() {
+        let cert_only = Settings {
+            tls_cert_path: Some("cert.pem".into()),
+            ..valid()
+        };
+        assert_eq!(rejected(cert_only), "tls_key_path");
+        let key_only = Settings {
+            tls_key_path: Some("key.pem".into()),
+            ..valid()
+        };
+        assert_eq!(rejected(key_only), "tls_cert_path");
+    }
+
+    #[test]
+    fn redirect_port() {
+        let tls = Settings {
+            tls_cert_path: Some("cert.pem".into()),
+            tls_key_path: Some("key.pem".into()),
+            port: 443,
+            ..valid()
+        };
+        assert!(Settings {
+            http_redirect_port: Some(80),
+            ..tls.clone()
+        }
+        .validate()
+        .is_ok());
+        let same_port = Settings {
+            http_redirect_port: Some(443),
+            ..tls
+        };
+        assert_eq!(rejected(same_port), "http_redirect_port");
+        let without_tls = Settings {
+            http_redirect_port: Some(80),
+            ..valid()
+        };
+        assert_eq!(rejected(without_tls), "http_redirect_port");
+    }
+
+    #[test]
+    fn cors_origins_only_in_staging() {
+        let staging = Settings {
+            environment: Environment::Staging,
+            ..valid()
+        };
+        assert_eq!(rejected(staging.clone()), "cors_origins");
+        let origins = vec!["https://staging.example.com".into()];
+        assert!(Settings {
+            cors_origins: origins.clone(),
+            ..staging
+        }
+        .validate()
+        .is_ok());
+        let production = Settings {
+            cors_origins: origins,
+            ..valid()
+        };
+        assert_eq!(rejected(production), "cors_origins");
+    }
+
+    #[test]
+    fn environment_names() {
+        assert_eq!("staging".parse(), Ok(Environment::Staging));
+        assert!("Staging".parse::<Environment>().is_err());
+    }
+
+    #[test]
+    fn pool_limits() {
+        let more_idle_than_max = Settings {
+            pool_min_connections: 11,
+            pool_size: 10,
+            ..valid()
+        };
+        assert_eq!(rejected(more_idle_than_max), "pool_min_connections");
+        let no_timeout = Settings {
+            pool_connect_timeout_seconds: 0,
+            ..valid()
+        };
+        assert_eq!(rejected(no_timeout), "pool_connect_timeout_seconds");
+    }
+
+    #[test]
+    fn short_session_secret() {
+        let settings = Settings {
+            session_secret: Some("too short".into()),
+            ..valid()
+        };
+        assert_eq!(rejected(settings), "session_secret");
+    }
+
+    #[test]
+    fn https_from_tls_or_public_url() {
+        assert!(!valid().is_https());
+        assert!(Settings {
+            tls_cert_path: Some("cert.pem".into()),
+            ..valid()
+        }
+        .is_https());
+        assert!(Settings {
+            public_url: "https://example.com".into(),
+            ..valid()
+        }
+        .is_https());
+    }
+
+    #[test]
+    fn smtp_settings() {
+        let smtp = Settings {
+            mail_transport: MailTransport::Smtp,
+            ..valid()
+        };
+        assert_eq!(rejected(smtp.clone()), "smtp_host");
+        let smtp = Settings {
+            smtp_host: Some("mail.example.com".into()),
+            ..smtp
+        };
+        assert!(smtp.validate().is_ok());
+        let username_only = Settings {
+            smtp_username: Some("howido".into()),
+            ..smtp
+        };
+        assert_eq!(rejected(username_only), "smtp_username");
+    }
+
+    #[test]
+    fn public_url_needs_a_scheme() {
+        let settings = Settings {
+            public_url: "example.com".into(),
+            ..valid()
+        };
+        assert_eq!(rejected(settings), "public_url");
+    }
+}
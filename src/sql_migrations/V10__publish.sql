@@ -0,0 +1,13 @@
+BEGIN;
+
+-- NULL while the how-to is a draft, which only its author can see.
+ALTER TABLE "howto" ADD COLUMN published_at timestamptz;
+
+-- Everything so far was readable as soon as it was created.
+UPDATE howto SET published_at = created_at;
+
+-- A draft without an author could never be published.
+ALTER TABLE "howto"
+    ADD CHECK (published_at IS NOT NULL OR author_id IS NOT NULL);
+
+COMMIT;
@@ -0,0 +1,74 @@
+BEGIN;
+
+CREATE TYPE howto_change AS ENUM (
+    'create',
+    'title',
+    'step_added',
+    'step_removed',
+    'step_updated',
+    'restore'
+);
+
+-- A full copy of a how-to after each change to it, numbered from 1.
+CREATE TABLE "howto_revision" (
+    id int GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    howto_id int NOT NULL REFERENCES "howto" ON DELETE CASCADE,
+    number int NOT NULL,
+    change howto_change NOT NULL,
+    restored_from int,
+    author_id int REFERENCES "app_user" ON DELETE SET NULL,
+    snapshot jsonb NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    UNIQUE (howto_id, number)
+);
+
+CREATE FUNCTION howto_revision_immutable() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'revisions can not be changed';
+END;
+$$ LANGUAGE plpgsql;
+
+-- Deleting is still allowed, for when the how-to itself is deleted.
+CREATE TRIGGER howto_revision_immutable
+    BEFORE UPDATE ON "howto_revision"
+    FOR EACH ROW EXECUTE FUNCTION howto_revision_immutable();
+
+-- Every how-to starts its history as it is now.
+INSERT INTO howto_revision (howto_id, number, change, snapshot, created_at)
+SELECT
+    howto.id,
+    1,
+    'create',
+    jsonb_build_object(
+        'title', howto.title,
+        'steps', COALESCE((
+            SELECT jsonb_agg(
+                jsonb_build_object(
+                    'stepId', step.id,
+                    'title', step.title,
+                    'seconds', step.seconds,
+                    'position', howto_step.position,
+                    'media', COALESCE((
+                        SELECT jsonb_agg(
+                            jsonb_build_object(
+                                'filename', step_media.filename,
+                                'mediaType', step_media.media_type,
+                                'position', step_media.position
+                            )
+                            ORDER BY step_media.position
+                        )
+                        FROM step_media
+                        WHERE step_media.step_id = step.id
+                    ), '[]')
+                )
+                ORDER BY howto_step.position, step.id
+            )
+            FROM howto_step, step
+            WHERE howto_step.howto_id = howto.id
+            AND step.id = howto_step.step_id
+        ), '[]')
+    ),
+    howto.created_at
+FROM howto;
+
+COMMIT;
@@ -0,0 +1,12 @@
+BEGIN;
+
+-- The number of the latest revision, which edits have to send back so they
+-- can't overwrite a change they haven't seen.
+ALTER TABLE "howto" ADD COLUMN version int NOT NULL DEFAULT 1;
+
+UPDATE howto
+SET version = COALESCE((
+    SELECT MAX(number) FROM howto_revision WHERE howto_id = howto.id
+), 1);
+
+COMMIT;
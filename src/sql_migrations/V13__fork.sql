@@ -0,0 +1,10 @@
+BEGIN;
+
+-- The how-to this one was copied from, shown as "forked from". Forks outlive
+-- their origin.
+ALTER TABLE "howto"
+    ADD COLUMN forked_from_id int REFERENCES "howto" ON DELETE SET NULL;
+
+CREATE INDEX howto_forked_from_id ON "howto" (forked_from_id);
+
+COMMIT;
@@ -0,0 +1,18 @@
+BEGIN;
+
+-- A step can have zero or more images, shown in `position` order.
+CREATE TABLE "step_media" (
+    id int GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    step_id int NOT NULL REFERENCES "step" ON DELETE CASCADE,
+    position int NOT NULL,
+    filename varchar(255) NOT NULL,
+    UNIQUE (step_id, position)
+);
+
+-- Every existing step has exactly one image, so it becomes the first one.
+INSERT INTO step_media (step_id, position, filename)
+SELECT id, 0, image_filename FROM step;
+
+ALTER TABLE "step" DROP COLUMN image_filename;
+
+COMMIT;
@@ -0,0 +1,9 @@
+BEGIN;
+
+CREATE TYPE media_type AS ENUM ('image', 'video');
+
+-- Everything uploaded so far is a still.
+ALTER TABLE "step_media"
+    ADD COLUMN media_type media_type NOT NULL DEFAULT 'image';
+
+COMMIT;
@@ -0,0 +1,12 @@
+BEGIN;
+
+-- "user" is reserved in postgres, hence the prefix.
+CREATE TABLE "app_user" (
+    id int GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    email varchar(255) NOT NULL UNIQUE,
+    display_name varchar(80) NOT NULL,
+    password_hash text NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+
+COMMIT;
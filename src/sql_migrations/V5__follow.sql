@@ -0,0 +1,21 @@
+BEGIN;
+
+-- How-tos created before there were users have no author.
+ALTER TABLE "howto"
+    ADD COLUMN author_id int REFERENCES "app_user" ON DELETE SET NULL,
+    ADD COLUMN created_at timestamptz NOT NULL DEFAULT now();
+
+CREATE INDEX howto_author_id ON howto (author_id, created_at);
+
+CREATE TABLE "follow" (
+    follower_id int NOT NULL REFERENCES "app_user" ON DELETE CASCADE,
+    followed_id int NOT NULL REFERENCES "app_user" ON DELETE CASCADE,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY (follower_id, followed_id),
+    CHECK (follower_id <> followed_id)
+);
+
+-- The primary key covers "who does X follow", this covers "who follows X".
+CREATE INDEX follow_followed_id ON follow (followed_id);
+
+COMMIT;
@@ -0,0 +1,11 @@
+BEGIN;
+
+ALTER TABLE "app_user"
+    ADD COLUMN bio varchar(500) NOT NULL DEFAULT '',
+    ADD COLUMN avatar_filename varchar(255);
+
+-- How long a step takes, so a how-to's total time can be shown.
+ALTER TABLE "step"
+    ADD COLUMN seconds int NOT NULL DEFAULT 0 CHECK (seconds >= 0);
+
+COMMIT;
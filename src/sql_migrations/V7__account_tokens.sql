@@ -0,0 +1,25 @@
+BEGIN;
+
+ALTER TABLE "app_user" ADD COLUMN email_verified_at timestamptz;
+
+-- Part of every session cookie. Bumping it logs the user out everywhere,
+-- which a password reset does.
+ALTER TABLE "app_user"
+    ADD COLUMN session_generation int NOT NULL DEFAULT 0;
+
+CREATE TYPE user_token_purpose AS ENUM ('verify_email', 'password_reset');
+
+-- Single-use links sent by email. Only a hash of each token is kept, so
+-- reading this table isn't enough to use one.
+CREATE TABLE "user_token" (
+    token_hash text PRIMARY KEY,
+    user_id int NOT NULL REFERENCES "app_user" ON DELETE CASCADE,
+    purpose user_token_purpose NOT NULL,
+    expires_at timestamptz NOT NULL,
+    used_at timestamptz,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE INDEX user_token_user_id ON user_token (user_id);
+
+COMMIT;
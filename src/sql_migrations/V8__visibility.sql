@@ -0,0 +1,15 @@
+BEGIN;
+
+CREATE TYPE visibility AS ENUM ('private', 'unlisted', 'public');
+
+-- Everything so far has been readable by anyone, so it stays that way. New
+-- how-tos start out private.
+ALTER TABLE "howto"
+    ADD COLUMN visibility visibility NOT NULL DEFAULT 'public';
+ALTER TABLE "howto" ALTER COLUMN visibility SET DEFAULT 'private';
+
+-- A private how-to without an author could never be read again.
+ALTER TABLE "howto"
+    ADD CHECK (visibility <> 'private' OR author_id IS NOT NULL);
+
+COMMIT;
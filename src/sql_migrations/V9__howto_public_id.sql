@@ -0,0 +1,8 @@
+BEGIN;
+
+-- What the API calls a how-to's id. gen_random_uuid() is built in from
+-- Postgres 13.
+ALTER TABLE "howto"
+    ADD COLUMN public_id uuid NOT NULL DEFAULT gen_random_uuid() UNIQUE;
+
+COMMIT;
@@ -0,0 +1,3 @@
+-- Lossy: drafts become readable, as everything was before.
+
+ALTER TABLE "howto" DROP COLUMN published_at;
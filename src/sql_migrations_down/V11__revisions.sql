@@ -0,0 +1,5 @@
+-- Lossy: all revision history is dropped.
+
+DROP TABLE "howto_revision";
+DROP FUNCTION howto_revision_immutable();
+DROP TYPE howto_change;
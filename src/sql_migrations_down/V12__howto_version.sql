@@ -0,0 +1,3 @@
+-- Lossy: none, the versions are the revision numbers.
+
+ALTER TABLE "howto" DROP COLUMN version;
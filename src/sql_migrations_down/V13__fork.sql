@@ -0,0 +1,3 @@
+-- Lossy: forks forget where they came from. Their steps and media stay.
+
+ALTER TABLE "howto" DROP COLUMN forked_from_id;
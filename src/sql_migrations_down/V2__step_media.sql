@@ -0,0 +1,16 @@
+-- Lossy: only the first image of each step survives, and steps without one
+-- get an empty filename.
+
+ALTER TABLE "step" ADD COLUMN image_filename varchar(255) NOT NULL DEFAULT '';
+
+UPDATE step
+SET image_filename = step_media.filename
+FROM step_media
+WHERE step_media.step_id = step.id
+AND step_media.position = (
+    SELECT MIN(position) FROM step_media AS first WHERE first.step_id = step.id
+);
+
+ALTER TABLE "step" ALTER COLUMN image_filename DROP DEFAULT;
+
+DROP TABLE "step_media";
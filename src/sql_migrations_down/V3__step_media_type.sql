@@ -0,0 +1,4 @@
+-- Lossy: videos stay in step_media, but are no longer marked as videos.
+
+ALTER TABLE "step_media" DROP COLUMN media_type;
+DROP TYPE media_type;
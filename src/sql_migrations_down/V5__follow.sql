@@ -0,0 +1,6 @@
+-- Lossy: who wrote each how-to, and when, is forgotten.
+
+DROP TABLE "follow";
+
+DROP INDEX howto_author_id;
+ALTER TABLE "howto" DROP COLUMN author_id, DROP COLUMN created_at;
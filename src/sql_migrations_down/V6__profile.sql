@@ -0,0 +1,6 @@
+-- Lossy: bios, avatars and step durations are dropped. Avatar files stay in
+-- the storage directory.
+
+ALTER TABLE "step" DROP COLUMN seconds;
+
+ALTER TABLE "app_user" DROP COLUMN bio, DROP COLUMN avatar_filename;
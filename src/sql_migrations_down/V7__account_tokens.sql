@@ -0,0 +1,9 @@
+-- Lossy: which emails were verified is forgotten, and outstanding links stop
+-- working. Everyone is logged out, since cookies from before no longer parse.
+
+DROP TABLE "user_token";
+DROP TYPE user_token_purpose;
+
+ALTER TABLE "app_user"
+    DROP COLUMN email_verified_at,
+    DROP COLUMN session_generation;
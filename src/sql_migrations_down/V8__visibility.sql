@@ -0,0 +1,4 @@
+-- Lossy: every how-to becomes readable by anyone again.
+
+ALTER TABLE "howto" DROP COLUMN visibility;
+DROP TYPE visibility;
@@ -0,0 +1,3 @@
+-- Lossy: links using the public ids stop working.
+
+ALTER TABLE "howto" DROP COLUMN public_id;
@@ -0,0 +1,218 @@
+//! HTTPS without a reverse proxy. The certificate is re-read from disk on
+//! SIGHUP, so a renewed certificate can be picked up without a restart.
+
+use crate::{settings::Settings, ServerSetupError};
+use actix_web::{
+    http::header, web, App, HttpRequest, HttpResponse, HttpServer,
+};
+use rustls::{
+    internal::pemfile, sign, ClientHello, NoClientAuth, PrivateKey,
+    ResolvesServerCert, ServerConfig,
+};
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+/// Builds the rustls config, or `None` when TLS isn't configured.
+pub fn server_config(
+    settings: &Settings,
+) -> Result<Option<ServerConfig>, ServerSetupError> {
+    let (cert_path, key_path) =
+        match (&settings.tls_cert_path, &settings.tls_key_path) {
+            (Some(cert), Some(key)) => (cert.clone(), key.clone()),
+            _ => return Ok(None),
+        };
+
+    let resolver = Arc::new(CertResolver {
+        current: RwLock::new(load_certified_key(&cert_path, &key_path)?),
+        cert_path,
+        key_path,
+    });
+    reload_on_sighup(resolver.clone());
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = resolver;
+    Ok(Some(config))
+}
+
+/// Hands out whatever certificate was loaded last.
+struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<sign::CertifiedKey>,
+}
+
+impl CertResolver {
+    fn reload(&self) {
+        match load_certified_key(&self.cert_path, &self.key_path) {
+            Ok(key) => {
+                *self.current.write().unwrap() = key;
+                log::info!("reloaded TLS certificate");
+            }
+            // Keep serving the old certificate rather than none at all.
+            Err(e) => log::error!("failed to reload TLS certificate: {:?}", e),
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(
+        &self,
+        _client_hello: ClientHello,
+    ) -> Option<sign::CertifiedKey> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+#[cfg(unix)]
+fn reload_on_sighup(resolver: Arc<CertResolver>) {
+    use actix_web::rt::signal::unix::{signal, SignalKind};
+
+    actix_web::rt::spawn(async move {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                log::error!("can't listen for SIGHUP, TLS won't reload: {}", e);
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            let resolver = resolver.clone();
+            // Reading the files is blocking, keep it off the event loop.
+            let _ = web::block(move || -> Result<(), ()> {
+                resolver.reload();
+                Ok(())
+            })
+            .await;
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn reload_on_sighup(_resolver: Arc<CertResolver>) {}
+
+fn load_certified_key(
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<sign::CertifiedKey, ServerSetupError> {
+    let mut reader = BufReader::new(
+        File::open(cert_path).map_err(|e| load_error(cert_path, e))?,
+    );
+    let certs = pemfile::certs(&mut reader)
+        .map_err(|_| load_error(cert_path, "not a PEM certificate chain"))?;
+    if certs.is_empty() {
+        return Err(load_error(cert_path, "no certificates found"));
+    }
+
+    // Accept either PKCS#8 or the older RSA format.
+    let mut keys = read_keys(key_path, pemfile::pkcs8_private_keys)?;
+    if keys.is_empty() {
+        keys = read_keys(key_path, pemfile::rsa_private_keys)?;
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| load_error(key_path, "no private key found"))?;
+    let signing_key = sign::any_supported_type(&key)
+        .map_err(|_| load_error(key_path, "unsupported private key type"))?;
+
+    Ok(sign::CertifiedKey::new(certs, Arc::new(signing_key)))
+}
+
+fn read_keys(
+    path: &Path,
+    parse: fn(&mut dyn BufRead) -> Result<Vec<PrivateKey>, ()>,
+) -> Result<Vec<PrivateKey>, ServerSetupError> {
+    let file = File::open(path).map_err(|e| load_error(path, e))?;
+    parse(&mut BufReader::new(file))
+        .map_err(|_| load_error(path, "not a PEM private key"))
+}
+
+fn load_error(path: &Path, message: impl Display) -> ServerSetupError {
+    ServerSetupError::LoadCertificate {
+        path: path.to_path_buf(),
+        message: message.to_string(),
+    }
+}
+
+/// A plain HTTP server that sends everything to the HTTPS one.
+pub fn redirect_server(
+    settings: &Settings,
+    redirect_port: u16,
+) -> Result<actix_web::dev::Server, ServerSetupError> {
+    let https_port = settings.port;
+    let server = HttpServer::new(move || {
+        App::new()
+            .data(https_port)
+            .default_service(web::to(redirect_to_https))
+    })
+    .shutdown_timeout(settings.shutdown_timeout_seconds)
+    .bind((settings.bind_address, redirect_port))?
+    .run();
+    Ok(server)
+}
+
+async fn redirect_to_https(
+    req: HttpRequest,
+    https_port: web::Data<u16>,
+) -> HttpResponse {
+    let path = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+    let location =
+        https_location(req.connection_info().host(), **https_port, path);
+
+    HttpResponse::MovedPermanently()
+        .header(header::LOCATION, location)
+        .finish()
+}
+
+/// The same `path` on `host`, over HTTPS on `https_port`.
+fn https_location(host: &str, https_port: u16, path: &str) -> String {
+    // Drop any port, but not the end of an IPv6 address like `[::1]`.
+    let hostname = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    let port = match https_port {
+        443 => String::new(),
+        port => format!(":{}", port),
+    };
+    format!("https://{}{}{}", hostname, port, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::https_location;
+
+    #[test]
+    fn default_port() {
+        assert_eq!(
+            https_location("example.com:8080", 443, "/a?b=c"),
+            "https://example.com/a?b=c"
+        );
+        assert_eq!(
+            https_location("example.com", 443, "/"),
+            "https://example.com/"
+        );
+    }
+
+    #[test]
+    fn other_port() {
+        assert_eq!(
+            https_location("example.com:8080", 8443, "/"),
+            "https://example.com:8443/"
+        );
+    }
+
+    #[test]
+    fn ipv6() {
+        assert_eq!(https_location("[::1]:8080", 443, "/"), "https://[::1]/");
+        assert_eq!(https_location("[::1]", 8443, "/"), "https://[::1]:8443/");
+    }
+}
@@ -0,0 +1,248 @@
+use crate::{
+    auth::CurrentUser,
+    avatars,
+    pagination::{Page, PageQuery},
+    validate_length, ServerError,
+};
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+use uuid::Uuid;
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct UserRow {
+    pub id: i32,
+    pub email: String,
+    pub display_name: String,
+    /// Goes in the session cookie, see `auth::remember`.
+    #[serde(skip)]
+    pub session_generation: i32,
+}
+
+/// How other users are shown: in follower lists, feeds and author blocks.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct UserSummary {
+    pub id: i32,
+    pub display_name: String,
+    #[serde(rename = "avatar", serialize_with = "avatars::serialize")]
+    pub avatar_filename: Option<String>,
+}
+
+pub async fn exists(db_pool: &PgPool, id: i32) -> Result<bool, ServerError> {
+    let (exists,): (bool,) =
+        sqlx::query_as("SELECT EXISTS (SELECT 1 FROM app_user WHERE id = $1)")
+            .bind(id)
+            .fetch_one(db_pool)
+            .await?;
+    Ok(exists)
+}
+
+pub async fn create_user(
+    db_pool: &PgPool,
+    email: &str,
+    display_name: &str,
+    password: &str,
+) -> Result<UserRow, ServerError> {
+    let email = email.trim().to_lowercase();
+    let display_name = display_name.trim();
+
+    if !email.contains('@') {
+        return Err(ServerError::ValidationError {
+            field: "email",
+            message: "Not an email address".into(),
+        });
+    }
+    validate_length(80, 1, display_name).map_err(|message| {
+        ServerError::ValidationError {
+            field: "displayName",
+            message,
+        }
+    })?;
+    validate_password(password)?;
+
+    let password_hash = hash_password(password)?;
+    let user = sqlx::query_as(
+        r#"
+INSERT INTO app_user (email, display_name, password_hash)
+VALUES ($1, $2, $3)
+RETURNING id, email, display_name, session_generation
+"#,
+    )
+    .bind(&email)
+    .bind(display_name)
+    .bind(&password_hash)
+    .fetch_one(db_pool)
+    .await;
+    match user {
+        Ok(user) => Ok(user),
+        // The unique index on email, which is the only one the insert can
+        // hit. Not a 500 with the database's message in it.
+        Err(sqlx::Error::Database(e))
+            if e.code().as_deref() == Some("23505") =>
+        {
+            Err(ServerError::ValidationError {
+                field: "email",
+                message: "There is already an account with this email address"
+                    .into(),
+            })
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn validate_password(password: &str) -> Result<(), ServerError> {
+    if password.chars().count() < 8 {
+        return Err(ServerError::ValidationError {
+            field: "password",
+            message: "Password too short. Min 8 characters".into(),
+        });
+    }
+    Ok(())
+}
+
+pub fn hash_password(password: &str) -> Result<String, ServerError> {
+    // A v4 uuid is 16 random bytes, which is what argon2 wants for a salt.
+    let salt = Uuid::new_v4();
+    argon2::hash_encoded(
+        password.as_bytes(),
+        salt.as_bytes(),
+        &argon2::Config::default(),
+    )
+    .map_err(|e| ServerError::ValidationError {
+        field: "password",
+        message: e.to_string(),
+    })
+}
+
+/// The user with this email and password, if there is one.
+pub async fn authenticate(
+    db_pool: &PgPool,
+    email: &str,
+    password: &str,
+) -> Result<Option<UserRow>, ServerError> {
+    let found: Option<(i32, String, String, i32, String)> = sqlx::query_as(
+        r#"
+SELECT id, email, display_name, session_generation, password_hash
+FROM app_user
+WHERE email = $1
+"#,
+    )
+    .bind(email.trim().to_lowercase())
+    .fetch_optional(db_pool)
+    .await?;
+
+    Ok(found.and_then(
+        |(id, email, display_name, session_generation, password_hash)| {
+            // An unreadable hash can't match anything, same as a wrong password.
+            match argon2::verify_encoded(&password_hash, password.as_bytes()) {
+                Ok(true) => Some(UserRow {
+                    id,
+                    email,
+                    display_name,
+                    session_generation,
+                }),
+                _ => None,
+            }
+        },
+    ))
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+struct ProfileRow {
+    display_name: String,
+    bio: String,
+    #[serde(rename = "avatar", serialize_with = "avatars::serialize")]
+    avatar_filename: Option<String>,
+    follower_count: i64,
+    following_count: i64,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+struct ProfileHowTo {
+    id: Uuid,
+    title: String,
+    created_at: DateTime<Utc>,
+    /// `None` for drafts, which only the author sees.
+    published_at: Option<DateTime<Utc>>,
+    step_count: i64,
+    /// The steps' durations added up.
+    seconds: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Profile {
+    id: i32,
+    #[serde(flatten)]
+    profile: ProfileRow,
+    how_tos: Page<ProfileHowTo>,
+}
+
+/// What anyone can see about a user, with the how-tos they've written,
+/// newest first. Only published, public ones are listed, unless it's the
+/// user's own profile.
+pub async fn profile(
+    db_pool: web::Data<PgPool>,
+    web::Path(id): web::Path<i32>,
+    page: web::Query<PageQuery>,
+    viewer: Option<CurrentUser>,
+) -> Result<HttpResponse, ServerError> {
+    let profile: ProfileRow = sqlx::query_as(
+        r#"
+SELECT
+    display_name,
+    bio,
+    avatar_filename,
+    (SELECT COUNT(*) FROM follow WHERE followed_id = app_user.id)
+        AS follower_count,
+    (SELECT COUNT(*) FROM follow WHERE follower_id = app_user.id)
+        AS following_count
+FROM app_user
+WHERE id = $1
+"#,
+    )
+    .bind(id)
+    .fetch_optional(&**db_pool)
+    .await?
+    .ok_or(ServerError::NotFound)?;
+
+    let how_tos: Vec<ProfileHowTo> = sqlx::query_as(
+        r#"
+SELECT
+    howto.public_id AS id,
+    howto.title,
+    howto.created_at,
+    howto.published_at,
+    COUNT(step.id) AS step_count,
+    COALESCE(SUM(step.seconds), 0) AS seconds
+FROM howto
+LEFT JOIN howto_step ON howto_step.howto_id = howto.id
+LEFT JOIN step ON step.id = howto_step.step_id
+WHERE howto.author_id = $1
+AND (
+    howto.visibility = 'public' AND howto.published_at IS NOT NULL
+    OR $1 = $4
+)
+GROUP BY howto.id
+ORDER BY howto.created_at DESC, howto.id DESC
+LIMIT $2 OFFSET $3
+"#,
+    )
+    .bind(id)
+    .bind(page.fetch_limit())
+    .bind(page.offset())
+    .bind(viewer.map(|user| user.0))
+    .fetch_all(&**db_pool)
+    .await?;
+
+    Ok(HttpResponse::Ok().json(Profile {
+        id,
+        profile,
+        how_tos: page.page(how_tos),
+    }))
+}
@@ -0,0 +1,126 @@
+//! Edits to a how-to say which version they were made against, so two people
+//! editing at once can't silently overwrite each other. The version is the
+//! number of the how-to's latest revision, sent back as `If-Match` or as a
+//! `version` field. A stale edit gets 409 Conflict with the how-to as it is
+//! now, to merge against and try again.
+
+use crate::{how_to_props, ServerError};
+use actix_web::{http::header, HttpRequest};
+use sqlx::{
+    postgres::{PgPool, Postgres},
+    Transaction,
+};
+
+/// The version from `If-Match`, or else from the body. One of them is
+/// required.
+pub fn expected(
+    req: &HttpRequest,
+    body: Option<i32>,
+) -> Result<i32, ServerError> {
+    let if_match = match req.headers().get(header::IF_MATCH) {
+        Some(value) => value,
+        None => return body.ok_or(ServerError::PreconditionRequired),
+    };
+    // Accepts `"3"`, `W/"3"` and a bare `3`.
+    if_match
+        .to_str()
+        .ok()
+        .map(|v| v.trim().trim_start_matches("W/").trim_matches('"'))
+        .and_then(|v| v.parse().ok())
+        .ok_or(ServerError::ValidationError {
+            field: "If-Match",
+            message: "Expected a how-to version".into(),
+        })
+}
+
+/// Locks the how-to for the rest of `tx`, and fails with `Conflict` unless
+/// it's still at `expected`. This is not a permission check: call
+/// `visibility::check_editor` first, since a conflict shows the how-to.
+pub async fn check(
+    tx: &mut Transaction<'_, Postgres>,
+    db_pool: &PgPool,
+    how_to_id: i32,
+    expected: i32,
+) -> Result<(), ServerError> {
+    let (version,): (i32,) =
+        sqlx::query_as("SELECT version FROM howto WHERE id = $1 FOR UPDATE")
+            .bind(how_to_id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .ok_or(ServerError::NotFound)?;
+    if version != expected {
+        let current = how_to_props(db_pool, how_to_id).await?;
+        return Err(ServerError::Conflict(Box::new(current)));
+    }
+    Ok(())
+}
+
+/// The `ETag` of a how-to at `version`.
+pub fn etag(version: i32) -> String {
+    format!("\"{}\"", version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn if_match(value: &str) -> HttpRequest {
+        TestRequest::default()
+            .header(header::IF_MATCH, value)
+            .to_http_request()
+    }
+
+    #[test]
+    fn from_if_match() {
+        for value in ["\"3\"", "W/\"3\"", "3", " \"3\" "] {
+            assert!(
+                matches!(expected(&if_match(value), None), Ok(3)),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn if_match_wins_over_the_body() {
+        assert!(matches!(expected(&if_match("\"3\""), Some(2)), Ok(3)));
+    }
+
+    #[test]
+    fn from_the_body() {
+        let req = TestRequest::default().to_http_request();
+        assert!(matches!(expected(&req, Some(2)), Ok(2)));
+    }
+
+    #[test]
+    fn required() {
+        let req = TestRequest::default().to_http_request();
+        assert!(matches!(
+            expected(&req, None),
+            Err(ServerError::PreconditionRequired)
+        ));
+    }
+
+    #[test]
+    fn not_a_version() {
+        for value in ["*", "\"abc\"", ""] {
+            assert!(
+                matches!(
+                    expected(&if_match(value), Some(2)),
+                    Err(ServerError::ValidationError {
+                        field: "If-Match",
+                        ..
+                    })
+                ),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn etag_round_trips() {
+        assert!(matches!(expected(&if_match(&etag(7)), None), Ok(7)));
+    }
+}
@@ -0,0 +1,278 @@
+//! Just enough MP4 and WebM parsing to read a clip's duration, so uploads can
+//! be limited without pulling in a demuxer.
+
+use std::{convert::TryInto, time::Duration};
+
+/// Reads the duration from the `moov/mvhd` box of an MP4 file.
+pub fn mp4_duration(bytes: &[u8]) -> Option<Duration> {
+    let moov = find_box(bytes, b"moov")?;
+    let mvhd = find_box(moov, b"mvhd")?;
+    let version = *mvhd.first()?;
+    let (timescale, duration) = if version == 1 {
+        // version, flags, creation time (8), modification time (8)
+        (read_u32(mvhd, 20)?, read_u64(mvhd, 24)?)
+    } else {
+        // version, flags, creation time (4), modification time (4)
+        (read_u32(mvhd, 12)?, read_u32(mvhd, 16)? as u64)
+    };
+    if timescale == 0 {
+        return None;
+    }
+    // `Duration` can't hold every u64 of seconds.
+    Duration::try_from_secs_f64(duration as f64 / timescale as f64).ok()
+}
+
+/// Returns the body of the first box of type `kind` at this level.
+fn find_box<'a>(mut bytes: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    while bytes.len() >= 8 {
+        let size = read_u32(bytes, 0)? as u64;
+        let (header, size) = match size {
+            // 64-bit size follows the type
+            1 => (16, read_u64(bytes, 8)?),
+            // box runs to the end of the file
+            0 => (8, bytes.len() as u64),
+            _ => (8, size),
+        };
+        if size < header || size > bytes.len() as u64 {
+            return None;
+        }
+        if &bytes[4..8] == kind {
+            return Some(&bytes[header as usize..size as usize]);
+        }
+        bytes = &bytes[size as usize..];
+    }
+    None
+}
+
+const EBML_SEGMENT: u64 = 0x1853_8067;
+const EBML_INFO: u64 = 0x1549_A966;
+const EBML_TIMECODE_SCALE: u64 = 0x2A_D7B1;
+const EBML_DURATION: u64 = 0x4489;
+
+/// Reads `Segment/Info/Duration` from a WebM file. Files written by a live
+/// recorder often leave this out, in which case there is nothing to read.
+pub fn webm_duration(bytes: &[u8]) -> Option<Duration> {
+    let segment = find_element(bytes, EBML_SEGMENT)?;
+    let info = find_element(segment, EBML_INFO)?;
+
+    let timecode_scale = match find_value(info, EBML_TIMECODE_SCALE) {
+        Some(scale) => read_uint(scale)?,
+        // nanoseconds per tick
+        None => 1_000_000,
+    };
+    let duration = find_value(info, EBML_DURATION)?;
+    let ticks = match duration.len() {
+        4 => f32::from_be_bytes(duration.try_into().ok()?) as f64,
+        8 => f64::from_be_bytes(duration.try_into().ok()?),
+        _ => return None,
+    };
+    // Rejects NaN, negative and too long to be a `Duration`.
+    Duration::try_from_secs_f64(ticks * timecode_scale as f64 / 1e9).ok()
+}
+
+/// Returns the body of the first element with `id` at this level. A cut-off
+/// body is returned as far as it goes, which is fine for containers.
+fn find_element(bytes: &[u8], id: u64) -> Option<&[u8]> {
+    find(bytes, id, true)
+}
+
+/// Like `find_element`, but only a complete body will do. Part of a number
+/// is a different number.
+fn find_value(bytes: &[u8], id: u64) -> Option<&[u8]> {
+    find(bytes, id, false)
+}
+
+fn find(mut bytes: &[u8], id: u64, allow_truncated: bool) -> Option<&[u8]> {
+    while !bytes.is_empty() {
+        // IDs keep their length marker, sizes don't.
+        let (element_id, id_len) = read_vint(bytes, false)?;
+        let (size, size_len) = read_vint(&bytes[id_len..], true)?;
+        let start = id_len + size_len;
+        // An unknown size (all value bits set) runs to the end of the parent.
+        let unknown = size == (1 << (7 * size_len)) - 1;
+        let end = if unknown {
+            bytes.len()
+        } else {
+            start.checked_add(size.try_into().ok()?)?
+        };
+        if end > bytes.len() {
+            // Truncated, but the element we want may still be in what's here.
+            if element_id == id && allow_truncated {
+                return Some(&bytes[start..]);
+            }
+            return None;
+        }
+        if element_id == id {
+            return Some(&bytes[start..end]);
+        }
+        bytes = &bytes[end..];
+    }
+    None
+}
+
+fn read_vint(bytes: &[u8], strip_marker: bool) -> Option<(u64, usize)> {
+    let first = *bytes.first()?;
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 || bytes.len() < len {
+        return None;
+    }
+    let mut value = if strip_marker {
+        (first as u64) & ((1 << (8 - len)) - 1)
+    } else {
+        first as u64
+    };
+    for b in &bytes[1..len] {
+        value = (value << 8) | *b as u64;
+    }
+    Some((value, len))
+}
+
+fn read_uint(bytes: &[u8]) -> Option<u64> {
+    if bytes.len() > 8 {
+        return None;
+    }
+    Some(bytes.iter().fold(0, |acc, b| (acc << 8) | *b as u64))
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut b = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        b.extend_from_slice(kind);
+        b.extend_from_slice(body);
+        b
+    }
+
+    fn mvhd_v0(timescale: u32, duration: u32) -> Vec<u8> {
+        let mut body = vec![0; 12];
+        body.extend_from_slice(&timescale.to_be_bytes());
+        body.extend_from_slice(&duration.to_be_bytes());
+        mp4_box(b"mvhd", &body)
+    }
+
+    fn mp4(mvhd: &[u8]) -> Vec<u8> {
+        let mut file = mp4_box(b"ftyp", b"isom");
+        file.extend(mp4_box(b"moov", mvhd));
+        file
+    }
+
+    #[test]
+    fn mp4_version_0() {
+        let file = mp4(&mvhd_v0(1000, 12_500));
+        assert_eq!(mp4_duration(&file), Some(Duration::from_millis(12_500)));
+    }
+
+    #[test]
+    fn mp4_version_1() {
+        let mut body = vec![1, 0, 0, 0];
+        body.extend_from_slice(&[0; 16]);
+        body.extend_from_slice(&600u32.to_be_bytes());
+        body.extend_from_slice(&(600u64 * 90).to_be_bytes());
+        let file = mp4(&mp4_box(b"mvhd", &body));
+        assert_eq!(mp4_duration(&file), Some(Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn mp4_64_bit_box_size() {
+        let mvhd = mvhd_v0(10, 30);
+        let mut moov = 1u32.to_be_bytes().to_vec();
+        moov.extend_from_slice(b"moov");
+        moov.extend_from_slice(&((mvhd.len() + 16) as u64).to_be_bytes());
+        moov.extend(mvhd);
+        assert_eq!(mp4_duration(&moov), Some(Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn mp4_zero_timescale() {
+        assert_eq!(mp4_duration(&mp4(&mvhd_v0(0, 30))), None);
+    }
+
+    #[test]
+    fn mp4_truncated_or_garbage() {
+        let file = mp4(&mvhd_v0(1000, 12_500));
+        for len in 0..file.len() {
+            assert_eq!(mp4_duration(&file[..len]), None);
+        }
+        assert_eq!(mp4_duration(b"not a video at all"), None);
+        assert_eq!(mp4_duration(&[0xff; 64]), None);
+    }
+
+    #[test]
+    fn mp4_duration_too_long() {
+        let mut body = vec![1, 0, 0, 0];
+        body.extend_from_slice(&[0; 16]);
+        body.extend_from_slice(&1u32.to_be_bytes());
+        body.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert_eq!(mp4_duration(&mp4(&mp4_box(b"mvhd", &body))), None);
+    }
+
+    fn element(id: &[u8], body: &[u8]) -> Vec<u8> {
+        assert!(body.len() < 0x7f);
+        let mut e = id.to_vec();
+        e.push(0x80 | body.len() as u8);
+        e.extend_from_slice(body);
+        e
+    }
+
+    const EBML_HEADER: &[u8] = &[0x1A, 0x45, 0xDF, 0xA3];
+    const SEGMENT: &[u8] = &[0x18, 0x53, 0x80, 0x67];
+    const INFO: &[u8] = &[0x15, 0x49, 0xA9, 0x66];
+    const TIMECODE_SCALE: &[u8] = &[0x2A, 0xD7, 0xB1];
+    const DURATION: &[u8] = &[0x44, 0x89];
+
+    fn webm(info: &[u8]) -> Vec<u8> {
+        let mut file = element(EBML_HEADER, &[]);
+        file.extend(element(SEGMENT, &element(INFO, info)));
+        file
+    }
+
+    #[test]
+    fn webm_default_timecode_scale() {
+        let file = webm(&element(DURATION, &2500f64.to_be_bytes()));
+        assert_eq!(webm_duration(&file), Some(Duration::from_millis(2500)));
+    }
+
+    #[test]
+    fn webm_timecode_scale() {
+        // One tick per second, and a 32-bit float duration.
+        let mut info = element(TIMECODE_SCALE, &1_000_000_000u32.to_be_bytes());
+        info.extend(element(DURATION, &4f32.to_be_bytes()));
+        assert_eq!(webm_duration(&webm(&info)), Some(Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn webm_without_duration() {
+        let info = element(TIMECODE_SCALE, &[0x0F, 0x42, 0x40]);
+        assert_eq!(webm_duration(&webm(&info)), None);
+    }
+
+    #[test]
+    fn webm_truncated_or_garbage() {
+        let file = webm(&element(DURATION, &2500f64.to_be_bytes()));
+        for len in 0..file.len() {
+            // Never panics, and a duration that's cut short isn't read.
+            assert_eq!(webm_duration(&file[..len]), None);
+        }
+        assert_eq!(webm_duration(b"not a video at all"), None);
+        assert_eq!(webm_duration(&[0x00; 64]), None);
+        assert_eq!(webm_duration(&[0xff; 64]), None);
+    }
+
+    #[test]
+    fn webm_duration_out_of_range() {
+        for ticks in [1e300, f64::INFINITY, f64::NAN, -1.0] {
+            let file = webm(&element(DURATION, &ticks.to_be_bytes()));
+            assert_eq!(webm_duration(&file), None, "{}", ticks);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Who can read a how-to. Public ones are listed anywhere, unlisted ones are
+//! readable by anyone with the link, and private ones only by their author.
+//! Drafts are private until they're published, whatever their visibility.
+//! Anything a reader can't see is a 404, so its existence isn't leaked.
+
+use crate::{
+    auth::CurrentUser,
+    public_id::{self, PublicId},
+    ServerError,
+};
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename = "visibility", rename_all = "lowercase")]
+pub enum Visibility {
+    Private,
+    Unlisted,
+    Public,
+}
+
+/// The how-to's author, if `viewer` may read it. `NotFound` otherwise.
+pub async fn check(
+    db_pool: &PgPool,
+    how_to_id: i32,
+    viewer: Option<&CurrentUser>,
+) -> Result<Option<i32>, ServerError> {
+    let (visibility, author_id, published): (Visibility, Option<i32>, bool) =
+        sqlx::query_as(
+            r#"
+SELECT visibility, author_id, published_at IS NOT NULL
+FROM howto
+WHERE id = $1
+"#,
+        )
+        .bind(how_to_id)
+        .fetch_optional(db_pool)
+        .await?
+        .ok_or(ServerError::NotFound)?;
+    let is_author = matches!(
+        (viewer, author_id),
+        (Some(user), Some(author_id)) if user.0 == author_id
+    );
+    let private = visibility == Visibility::Private || !published;
+    if private && !is_author {
+        return Err(ServerError::NotFound);
+    }
+    Ok(author_id)
+}
+
+/// Only the author may change it, and only if they can see it to begin with.
+pub async fn check_author(
+    db_pool: &PgPool,
+    how_to_id: i32,
+    user: &CurrentUser,
+) -> Result<(), ServerError> {
+    match check(db_pool, how_to_id, Some(user)).await? {
+        Some(author_id) if author_id == user.0 => Ok(()),
+        _ => Err(ServerError::Forbidden),
+    }
+}
+
+/// Who may change a how-to's title, steps or media: its author. How-tos
+/// without one, made anonymously or before there were accounts, are always
+/// public and stay open to anyone, as they were.
+pub async fn check_editor(
+    db_pool: &PgPool,
+    how_to_id: i32,
+    user: Option<&CurrentUser>,
+) -> Result<(), ServerError> {
+    match (check(db_pool, how_to_id, user).await?, user) {
+        (None, _) => Ok(()),
+        (Some(author_id), Some(user)) if author_id == user.0 => Ok(()),
+        (Some(_), None) => Err(ServerError::Unauthorized),
+        (Some(_), Some(_)) => Err(ServerError::Forbidden),
+    }
+}
+
+/// Private how-tos need someone who can still read them.
+pub fn validate(
+    visibility: Visibility,
+    author_id: Option<i32>,
+) -> Result<(), ServerError> {
+    if visibility == Visibility::Private && author_id.is_none() {
+        return Err(ServerError::ValidationError {
+            field: "visibility",
+            message: "Log in to make a how-to private".into(),
+        });
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct VisibilityInput {
+    visibility: Visibility,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VisibilityData {
+    id: Uuid,
+    visibility: Visibility,
+}
+
+pub async fn set_visibility(
+    user: CurrentUser,
+    db_pool: web::Data<PgPool>,
+    web::Path(public_id): web::Path<PublicId>,
+    json: web::Json<VisibilityInput>,
+) -> Result<HttpResponse, ServerError> {
+    let id = public_id::resolve(&db_pool, public_id).await?;
+    check_author(&db_pool, id, &user).await?;
+    sqlx::query("UPDATE howto SET visibility = $2 WHERE id = $1")
+        .bind(id)
+        .bind(json.visibility)
+        .execute(&**db_pool)
+        .await?;
+    Ok(HttpResponse::Ok().json(VisibilityData {
+        id: public_id.0,
+        visibility: json.visibility,
+    }))
+}